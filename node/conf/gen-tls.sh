@@ -0,0 +1,31 @@
+#!/bin/sh
+# Makes a self-signed CA, unless there's one already, and a certificate signed by it for a node, for
+# trying TLS out locally. The certificate names the node's id, taken from the node's data directory
+# or created there the way the node would, so run it once per node with the same CA directory.
+# Usage: conf/gen-tls.sh [CA dir] [ip or host the node is contacted on] [node's data dir]
+set -e
+dir=${1:-conf}
+host=${2:-127.0.0.1}
+data=${3:-data}
+case $host in
+    *[!0-9.]*) san="DNS:$host" ;;
+    *) san="IP:$host" ;;
+esac
+
+mkdir -p "$data"
+if [ ! -f "$data/node_id" ]; then
+    openssl rand -hex 16 | sed 's/^\(.\{8\}\)\(.\{4\}\).\(.\{3\}\).\(.\{3\}\)\(.\{12\}\)$/\1-\2-4\3-8\4-\5/' \
+        > "$data/node_id"
+fi
+id=$(tr -d ' \n' < "$data/node_id")
+
+if [ ! -f "$dir/ca.pem" ]; then
+    openssl req -x509 -newkey rsa:2048 -nodes -days 365 -subj "/CN=piko test CA" \
+        -keyout "$dir/ca.key" -out "$dir/ca.pem"
+fi
+openssl req -newkey rsa:2048 -nodes -subj "/CN=$host" \
+    -keyout "$data/node.key" -out "$data/node.csr"
+printf "subjectAltName=%s,URI:urn:uuid:%s\n" "$san" "$id" > "$data/node.ext"
+openssl x509 -req -days 365 -in "$data/node.csr" -CA "$dir/ca.pem" -CAkey "$dir/ca.key" -CAcreateserial \
+    -extfile "$data/node.ext" -out "$data/node.pem"
+rm "$data/node.csr" "$data/node.ext"
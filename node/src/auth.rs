@@ -0,0 +1,65 @@
+use std::sync::{Arc, Mutex};
+
+use hmac::{Hmac, Mac, NewMac};
+use lazy_static::lazy_static;
+use sha2::Sha256;
+
+use crate::error::{Error, Result};
+use crate::proto::ProtoError;
+
+lazy_static! {
+    static ref SECRET: Mutex<Option<Arc<Vec<u8>>>> = Mutex::new(None);
+}
+
+// Length of the tag closing every parcel sealed with the cluster's secret
+const TAG: usize = 32;
+
+/// Secret the cluster seals its parcels with, if it shares one.
+pub fn get_secret() -> Option<Arc<Vec<u8>>> {
+    SECRET.lock().unwrap().clone()
+}
+
+pub fn set_secret(secret: &[u8]) {
+    *SECRET.lock().unwrap() = Some(Arc::new(secret.to_vec()));
+}
+
+/// The encoded parcel `buf` closed with an HMAC-SHA256 tag under `secret`, so only nodes knowing
+/// the secret could have written it.
+pub fn seal(secret: &[u8], mut buf: Vec<u8>) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC takes keys of any length");
+    mac.update(&buf);
+    buf.extend_from_slice(&mac.finalize().into_bytes());
+    buf
+}
+
+/// The encoded parcel sealed in `buf`, failing if it wasn't sealed under `secret`.
+pub fn open(secret: &[u8], mut buf: Vec<u8>) -> Result<Vec<u8>> {
+    if buf.len() < TAG {
+        return Err(unauthenticated("parcel isn't sealed"));
+    }
+    let tag = buf.split_off(buf.len() - TAG);
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC takes keys of any length");
+    mac.update(&buf);
+    mac.verify(&tag).map_err(|_| unauthenticated("parcel isn't sealed with the cluster's secret"))?;
+    Ok(buf)
+}
+
+/// Seals `buf` with the cluster's secret, if it has one.
+pub fn seal_parcel(buf: Vec<u8>) -> Vec<u8> {
+    match get_secret() {
+        Some(secret) => seal(&secret, buf),
+        None => buf,
+    }
+}
+
+/// Opens `buf` with the cluster's secret, if it has one.
+pub fn open_parcel(buf: Vec<u8>) -> Result<Vec<u8>> {
+    match get_secret() {
+        Some(secret) => open(&secret, buf),
+        None => Ok(buf),
+    }
+}
+
+pub(crate) fn unauthenticated(detail: &str) -> Error {
+    Error::Protocol { code: ProtoError::Unauthenticated, detail: detail.to_string() }
+}
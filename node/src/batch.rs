@@ -0,0 +1,98 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+use log::debug;
+
+use crate::error::{Error, Result};
+use crate::proto::{ResourceRequest, ResourceRelease};
+use crate::req::publish::pub_req;
+use crate::state::State;
+
+// Upper bound on requests carried by a single parcel
+const MAX_BATCH: usize = 256;
+
+type Submission = (ResourceRequest, oneshot::Sender<Result<()>>);
+
+/// Coalesces outgoing resource requests into batches.
+///
+/// The first request to arrive opens a batch, which stays open for `linger` and then takes
+/// whatever else is already waiting. The batch goes out as one parcel per neighbour and each
+/// submitter hears back as soon as its own request is settled. Batches are published
+/// concurrently, so a slow batch never holds up the next one.
+pub struct RequestBatcher {
+    sender: UnboundedSender<Submission>,
+}
+
+impl RequestBatcher {
+    /// Starts the batching task. Must be called from within the tokio runtime.
+    /// Releases that neighbours hand over with their grants are passed on to `releases`.
+    pub fn new(state: Arc<RwLock<State>>, linger: Duration, releases: UnboundedSender<ResourceRelease>) -> RequestBatcher {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(run(state, linger, receiver, releases));
+
+        RequestBatcher { sender }
+    }
+
+    /// Publishes `req` to every active neighbour along with the rest of its batch.
+    pub async fn submit(&self, req: ResourceRequest) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self.sender.send((req, tx)).is_err() {
+            return Err(stopped());
+        }
+        rx.await.unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+fn stopped() -> Error {
+    Error::Ordering("the request batcher stopped".to_string())
+}
+
+async fn run(state: Arc<RwLock<State>>, linger: Duration, mut receiver: UnboundedReceiver<Submission>,
+             releases: UnboundedSender<ResourceRelease>) {
+    while let Some(first) = receiver.recv().await {
+        let mut batch = vec![first];
+
+        if linger > Duration::from_millis(0) {
+            let deadline = tokio::time::sleep(linger);
+            tokio::pin!(deadline);
+            while batch.len() < MAX_BATCH {
+                tokio::select! {
+                    _ = &mut deadline => break,
+                    submission = receiver.recv() => match submission {
+                        Some(submission) => batch.push(submission),
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        while batch.len() < MAX_BATCH {
+            match receiver.try_recv() {
+                Ok(submission) => batch.push(submission),
+                Err(_) => break,
+            }
+        }
+
+        debug!("Publishing batch of {} requests", batch.len());
+        let (requests, waiters): (Vec<ResourceRequest>, Vec<oneshot::Sender<Result<()>>>) = batch.into_iter().unzip();
+
+        let (neighbours, has_quorum, quorum) = {
+            let state_ref = state.read().unwrap();
+            (state_ref.get_neighbour_connections(), state_ref.has_quorum(), state_ref.quorum())
+        };
+        if !has_quorum {
+            // lost the majority while the batch was lingering
+            for waiter in waiters {
+                let _ = waiter.send(Err(Error::NoQuorum));
+            }
+            continue;
+        }
+
+        let releases = releases.clone();
+        tokio::spawn(async move { pub_req(&neighbours, quorum, requests, waiters, &releases).await });
+    }
+}
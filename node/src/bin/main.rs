@@ -1,40 +1,50 @@
 extern crate config;
-extern crate rayon;
 extern crate sha2;
 extern crate num;
 extern crate num_derive;
 extern crate lazy_static;
 extern crate log;
 extern crate fern;
-extern crate crossbeam_channel;
+extern crate tokio;
 
 use config::*;
 
-use std::net::{TcpListener, ToSocketAddrs};
+use std::net::ToSocketAddrs;
+use tokio::net::TcpListener;
 use std::net::SocketAddr;
 use std::str::FromStr;
 use piko::dsc::dsc;
 use piko::state::{Mode, State, Node};
-use std::collections::{HashMap, BinaryHeap};
+use std::collections::HashMap;
 use std::env;
 use std::env::current_dir;
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 
-use piko::net::listener_thread;
-use std::sync::{Arc, RwLock, Mutex};
+use piko::net::{listener_thread, set_timeouts, Timeouts};
+use std::sync::{Arc, RwLock};
 
 use piko::internal::TaskSignal;
-use piko::proto::{ResourceRequest, ResourceRelease, get_proto_version};
+use piko::proto::{Cluster, ReleaseMode, EngineKind, get_proto_version, set_release_mode, get_release_mode, set_engine, get_engine, set_max_parcel_size, DEFAULT_MAX_PARCEL_SIZE};
 
 use fern::colors::{Color, ColoredLevelConfig};
-use log::{info};
+use log::{info, error};
 
 use piko::heartbeat::heartbeat;
 use piko::client::{client_listener};
-use piko::wrk::wrk;
-use piko::semaphore::OrdSemaphore;
-use chrono::{DateTime, Utc};
-use crossbeam_channel::{Sender, Receiver};
+use piko::outbox::flush;
+use piko::engine::OrderingEngine;
+use piko::error::Result;
+use piko::engine::lamport::LamportEngine;
+use piko::engine::raft::RaftEngine;
+use piko::history::{History, DEFAULT_HISTORY};
+use piko::phi::DEFAULT_PHI_THRESHOLD;
+use piko::identity;
+use piko::tls::{set_tls, Tls};
+use piko::auth::set_secret;
+use piko::shutdown::{stop_requested, shutdown};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use uuid::Uuid;
 
 fn setup_logger() {
     let colors_line = ColoredLevelConfig::new()
@@ -66,7 +76,8 @@ fn setup_logger() {
         .unwrap();
 }
 
-fn main() {
+#[tokio::main]
+async fn main() -> Result<()> {
     setup_logger();
 
     let mut settings = Config::default();
@@ -99,6 +110,69 @@ fn main() {
         .expect("Missing client socket name");
     let external_addr = settings
         .get_str("node.external_addr");
+    let batch_linger = settings
+        .get_int("node.batch_linger_ms")
+        .unwrap_or(0);
+    let release_linger = settings
+        .get_int("node.release_linger_ms")
+        .unwrap_or(5);
+    let release_mode = settings
+        .get_str("cluster.release_mode")
+        .unwrap_or_else(|_| "broadcast".to_string());
+    let engine_kind = settings
+        .get_str("cluster.engine")
+        .unwrap_or_else(|_| "lamport".to_string());
+    let election_timeout = settings
+        .get_int("raft.election_timeout_ms")
+        .unwrap_or(300);
+    let raft_heartbeat = settings
+        .get_int("raft.heartbeat_ms")
+        .unwrap_or(50);
+    let shutdown_grace = settings
+        .get_int("node.shutdown_grace_ms")
+        .unwrap_or(5000);
+    let history_size = settings
+        .get_int("node.history_size")
+        .unwrap_or(DEFAULT_HISTORY as i64);
+    let max_parcel_size = settings
+        .get_int("node.max_parcel_size")
+        .unwrap_or(DEFAULT_MAX_PARCEL_SIZE as i64);
+    let connect_timeout = settings
+        .get_int("node.connect_timeout_ms")
+        .unwrap_or(3000);
+    let read_timeout = settings
+        .get_int("node.read_timeout_ms")
+        .unwrap_or(10000);
+    let write_timeout = settings
+        .get_int("node.write_timeout_ms")
+        .unwrap_or(10000);
+    let request_timeout = settings
+        .get_int("node.request_timeout_ms")
+        .unwrap_or(10000);
+    let heartbeat_period = settings
+        .get_int("node.heartbeat_ms")
+        .unwrap_or(5000);
+    let heartbeat_timeout = settings
+        .get_int("node.heartbeat_timeout")
+        .unwrap_or(5);
+    let phi_threshold = settings
+        .get_float("node.phi_threshold")
+        .unwrap_or(DEFAULT_PHI_THRESHOLD);
+    let data_dir = settings
+        .get_str("node.data_dir")
+        .unwrap_or_else(|_| "data".to_string());
+    let tls_cert = settings
+        .get_str("tls.cert")
+        .ok();
+    let tls_server_name = settings
+        .get_str("tls.server_name")
+        .ok();
+    let cluster_secret = settings
+        .get_str("cluster.secret")
+        .ok();
+    let cluster_name = settings
+        .get_str("cluster.name")
+        .expect("Missing cluster name.");
     let neighbour_host_names = settings
         .get_array("cluster.neighbours")
         .expect("Missing cluster settings.");
@@ -110,6 +184,10 @@ fn main() {
         neighbour_socket_addresses.push(addr);
     }
 
+    let cluster_size = settings
+        .get_int("cluster.size")
+        .unwrap_or(neighbour_socket_addresses.len() as i64 + 1);
+
     let addr = socket_name.to_socket_addrs().expect("Error parsing host name").next().unwrap();
     let client_addr = client_socket_name.to_socket_addrs().expect("Error parsing client host name").next().unwrap();
     let external_addr = match external_addr {
@@ -117,9 +195,48 @@ fn main() {
         Err(_e) => { None }
     };
 
+    let batch_linger = Duration::from_millis(batch_linger as u64);
+    let release_linger = Duration::from_millis(release_linger as u64);
+    let election_timeout = Duration::from_millis(election_timeout as u64);
+    let raft_heartbeat = Duration::from_millis(raft_heartbeat as u64);
+    let shutdown_grace = Duration::from_millis(shutdown_grace as u64);
+    let heartbeat_period = Duration::from_millis(heartbeat_period as u64);
+
+    set_release_mode(ReleaseMode::from_str(release_mode.as_str()).expect("Error parsing release mode"));
+    set_engine(EngineKind::from_str(engine_kind.as_str()).expect("Error parsing ordering engine"));
+    set_max_parcel_size(max_parcel_size as usize);
+    set_timeouts(Timeouts {
+        connect: Duration::from_millis(connect_timeout as u64),
+        read: Duration::from_millis(read_timeout as u64),
+        write: Duration::from_millis(write_timeout as u64),
+        request: Duration::from_millis(request_timeout as u64),
+    });
+
+    // nodes only take parcels from members, known by their certificate or the cluster's secret
+    if tls_cert.is_none() && cluster_secret.is_none() {
+        error!("Neither [tls] nor cluster.secret is configured, refusing to serve the cluster unauthenticated");
+        std::process::exit(1);
+    }
+    let id = identity::load_or_create(Path::new(&data_dir)).expect("Couldn't load node id");
+    info!("Node id is {}", id);
+    if let Some(cert) = tls_cert {
+        let key = settings.get_str("tls.key").expect("Missing TLS key.");
+        let ca = settings.get_str("tls.ca").expect("Missing TLS CA.");
+        let tls = Tls::load(Path::new(&cert), Path::new(&key), Path::new(&ca), tls_server_name).expect("Error loading TLS certificates");
+        if tls.node_id() != id {
+            error!("{} was issued to node {}, this is node {}", cert, tls.node_id(), id);
+            std::process::exit(1);
+        }
+        set_tls(tls);
+        info!("Using TLS with certificate {}", cert);
+    }
+    if let Some(secret) = cluster_secret {
+        set_secret(secret.as_bytes());
+        info!("Sealing parcels with the cluster's secret");
+    }
     info!("Using Protocol Version {}", get_proto_version());
 
-    let cluster_socket = match TcpListener::bind(addr) {
+    let cluster_socket = match TcpListener::bind(addr).await {
         Ok(listener) => {
             info!("Listening to cluster on {}.", listener.local_addr().unwrap());
             listener
@@ -127,7 +244,7 @@ fn main() {
         Err(error) => panic!("Error binding cluster socket: {}", error),
     };
 
-    let client_socket = match TcpListener::bind(client_addr) {
+    let client_socket = match TcpListener::bind(client_addr).await {
         Ok(listener) => {
             info!("Listening to clients on {}.", listener.local_addr().unwrap());
             listener
@@ -135,78 +252,99 @@ fn main() {
         Err(error) => panic!("Error binding client socket: {}", error),
     };
 
-    let neighbours = HashMap::<u16, Node>::new();
+    let cluster_id = identity::load_cluster_id(Path::new(&data_dir)).expect("Couldn't load cluster id");
 
-    let (pledge_sender, work_receiver): (Sender<ResourceRelease>, Receiver<ResourceRelease>) = crossbeam_channel::unbounded();
+    let neighbours = HashMap::<Uuid, Node>::new();
 
-    // Initiate state & shared data structures
-    let state = Arc::new(RwLock::new(State::new(Mode::Dsc, name, addr, external_addr, neighbours)));
-    let pledge_queue: Arc<Mutex<BinaryHeap<ResourceRequest>>> = Arc::new(Mutex::new(BinaryHeap::new()));
-    let semaphore: Arc<OrdSemaphore<DateTime<Utc>>> = Arc::new(OrdSemaphore::new());
-    let pending_messages: Arc<Mutex<HashMap<u64, (ResourceRelease, bool)>>> = Arc::new(Mutex::new(HashMap::new()));
+    // Initiate state & ordering engine
+    let mut state = State::new(id, Mode::Dsc, name, addr, external_addr, neighbours);
+    state.history = Arc::new(History::new(history_size as usize));
+    state.cluster = Cluster { name: cluster_name, id: cluster_id };
+    state.size = cluster_size as usize;
+    let state = Arc::new(RwLock::new(state));
+    let engine: Arc<dyn OrderingEngine> = match get_engine() {
+        EngineKind::Lamport => Arc::new(LamportEngine::new(state.clone(), batch_linger, get_release_mode())),
+        EngineKind::Raft => {
+            let engine = RaftEngine::open(state.clone(), Path::new(&data_dir), election_timeout, raft_heartbeat).expect("Couldn't load the raft log");
+            // a node without neighbours to join forms the cluster
+            if neighbour_socket_addresses.is_empty() {
+                engine.bootstrap().expect("Couldn't start the raft log");
+            }
+            Arc::new(engine)
+        }
+    };
 
     // Start network listener thread
-    let state_ref = state.clone();
-    let pledge_queue_ref = pledge_queue.clone();
-    let semaphore_ref = semaphore.clone();
-    let pledge_sender_ref = pledge_sender.clone();
-    let pending_messages_ref = pending_messages.clone();
-
-    rayon::spawn(move || listener_thread(
+    tokio::spawn(listener_thread(
         cluster_socket,
-        state_ref,
-        pledge_queue_ref,
-        semaphore_ref,
-        pledge_sender_ref,
+        state.clone(),
+        engine.clone(),
     ));
 
     // Start client listener thread
-    let state_ref = state.clone();
-    let pledge_queue_ref = pledge_queue.clone();
-    let semaphore_ref = semaphore.clone();
-    rayon::spawn(move || client_listener(
+    tokio::spawn(client_listener(
         client_socket,
-        state_ref,
-        pledge_queue_ref,
-        semaphore_ref,
-        pending_messages_ref,
+        state.clone(),
+        engine.clone(),
     ));
 
+    // Start flushing releases that no grant picked up
+    if get_release_mode() == ReleaseMode::Deferred {
+        tokio::spawn(flush(state.clone(), release_linger));
+    }
+
     // Start heartbeat thread
     let state_ref = state.clone();
-    let (_monitor_sender, monitor_receiver): (Sender<TaskSignal>, Receiver<TaskSignal>) = crossbeam_channel::unbounded();
-    rayon::spawn(move || heartbeat(
+    let (monitor_sender, monitor_receiver): (UnboundedSender<TaskSignal>, UnboundedReceiver<TaskSignal>) = mpsc::unbounded_channel();
+    tokio::spawn(heartbeat(
         state_ref,
-        5,
-        5,
+        heartbeat_period,
+        heartbeat_timeout as u32,
+        phi_threshold,
         monitor_receiver,
     ));
 
 
+    // The engine keeps running while a shutdown waits for publishes in flight
+    let mut stopped = Box::pin(async {
+        stop_requested().await;
+        shutdown(state.clone(), engine.clone(), monitor_sender, shutdown_grace).await;
+    });
+
     info!("Started main worker thread!");
     loop {
-        let state_lock = state.read().unwrap();
-        let mode = &state_lock.mode;
+        let mode = state.read().unwrap().mode.clone();
         info!("Mode: {}", mode);
         match mode {
             Mode::Dsc => {
-                drop(state_lock);
-                dsc(state.clone(), &neighbour_socket_addresses);
+                tokio::select! {
+                    joined = dsc(state.clone(), &neighbour_socket_addresses) => {
+                        // the cluster won't have this node, there's nothing to retry
+                        if let Err(e) = joined {
+                            error!("Couldn't join the cluster, {}", e);
+                            return Err(e);
+                        }
+                        // remember the cluster formed or joined, the node can't be moved to another
+                        if let Some(cluster_id) = state.read().unwrap().cluster.id {
+                            identity::store_cluster_id(Path::new(&data_dir), cluster_id).expect("Couldn't store cluster id");
+                        }
+                    }
+                    _ = &mut stopped => break,
+                }
             }
             Mode::Wrk => {
-                drop(state_lock);
-                wrk(state.clone(), pledge_queue.clone(),
-                    &work_receiver, pending_messages.clone());
+                tokio::select! {
+                    _ = engine.run() => {}
+                    _ = &mut stopped => break,
+                }
             }
             Mode::Err => {}
             Mode::Panic => {}
-            Mode::Shutdown => {
-                info!("Bye!");
-                break;
-            }
+            Mode::Shutdown => break,
             _ => {}
         }
     }
 
-    info!("Shutting down.");
+    info!("Bye!");
+    Ok(())
 }
\ No newline at end of file
@@ -1,28 +1,18 @@
-use std::sync::{RwLock, Arc, Mutex};
+use std::sync::{RwLock, Arc};
 
-use std::collections::{VecDeque, HashMap, BinaryHeap};
 
-use std::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use serde::{Serialize, Deserialize};
-use byteorder::{ReadBytesExt, WriteBytesExt};
-use std::io::{Read, Write};
-use std::error::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::proto::{ResourceRequest, ResourceRelease};
-use crate::req::publish::pub_req;
-use crate::state::State;
+use crate::engine::OrderingEngine;
+use crate::state::{State, Mode};
 
 use log::{error, debug, warn};
 
-use crate::internal::TaskSignal;
-use chrono::{Utc, DateTime};
-use crate::semaphore::OrdSemaphore;
-use std::time::Duration;
-
-pub struct Client<'a> {
-    identity: u64,
-    message_queue: VecDeque<&'a Vec<u8>>,
-}
+use crate::error::{Error, Result};
+use crate::net::{accept, ACCEPT_BACKOFF};
+use crate::tls::{Listener, Stream};
 
 #[derive(Serialize, Deserialize)]
 pub enum ClientRes {
@@ -93,14 +83,14 @@ impl ClientReq {
 }
 
 // Read Request from client
-fn read_req(stream: &mut TcpStream) -> Result<ClientReq, Box<dyn Error>> {
-    let count = stream.read_u8()?;
+async fn read_req(stream: &mut Stream) -> Result<ClientReq> {
+    let count = stream.read_u8().await?;
 
     // debug!("Expecting {} bytes", count);
 
     let mut buf = vec![0u8; count as usize];
 
-    stream.read_exact(&mut buf)?;
+    stream.read_exact(&mut buf).await?;
 
     let client_req: ClientReq = serde_cbor::from_slice(buf.as_slice())?;
 
@@ -108,52 +98,63 @@ fn read_req(stream: &mut TcpStream) -> Result<ClientReq, Box<dyn Error>> {
 }
 
 // Write Response to client
-fn write_res(stream: &mut TcpStream, res: ClientRes) {
+async fn write_res(stream: &mut Stream, res: ClientRes) {
     let buf = serde_cbor::to_vec(&res).unwrap();
 
     // debug!("Writing {} bytes to client", buf.len());
-    match stream.write_u8(buf.len() as u8) {
+    match stream.write_u8(buf.len() as u8).await {
         Ok(_) => {}
         Err(err) => {
             warn!("Client write error! {}", err )
         }
     };
-    match stream.write_all(buf.as_slice()){
+    match stream.write_all(buf.as_slice()).await {
         Ok(_) => {}
         Err(err) => {
             warn!("Client write error! {}", err )
         }
     };
+    if let Err(err) = stream.flush().await {
+        warn!("Client write error! {}", err)
+    }
 }
 
-fn ok(stream: &mut TcpStream) {
-    write_res(stream, ClientRes::Success { message: "Ok".to_string(), bytes: vec![] });
+async fn ok(stream: &mut Stream) {
+    write_res(stream, ClientRes::Success { message: "Ok".to_string(), bytes: vec![] }).await;
 }
 
-fn ok_with_message(stream: &mut TcpStream, message: &str) {
-    write_res(stream, ClientRes::Success { message: message.to_string(), bytes: vec![] });
+async fn ok_with_message(stream: &mut Stream, message: &str) {
+    write_res(stream, ClientRes::Success { message: message.to_string(), bytes: vec![] }).await;
 }
 
-fn err(stream: &mut TcpStream, message: &str) {
-    write_res(stream, ClientRes::Error { message: message.to_string() });
+async fn err(stream: &mut Stream, message: &str) {
+    write_res(stream, ClientRes::Error { message: message.to_string() }).await;
 }
 
-pub fn client_listener(listener: TcpListener, state: Arc<RwLock<State>>, // Node state & listener
-                       resource_queue: Arc<Mutex<BinaryHeap<ResourceRequest>>>, // Resource queue
-                       semaphore: Arc<OrdSemaphore<DateTime<Utc>>>, // Total order slemaphore
-                       pending_messages: Arc<Mutex<HashMap<u64, (ResourceRelease, bool)>>>) {
-    for stream in listener.incoming() {
-        let mut stream = stream.unwrap();
+pub async fn client_listener(listener: TcpListener, state: Arc<RwLock<State>>, engine: Arc<dyn OrderingEngine>) { // Listener, state & ordering engine
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Couldn't accept a client connection: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
 
-        let client_list: Arc<RwLock<HashMap<u64, RwLock<Client>>>> = Arc::new(RwLock::new(HashMap::<u64, RwLock<Client>>::new()));
-        let state_ref = state.clone();
-        let pledge_queue = Arc::clone(&resource_queue);
-        let pending_messages = pending_messages.clone();
-        let semaphore = semaphore.clone();
+        let state = state.clone();
+        let engine = engine.clone();
 
-        rayon::spawn(move || {
+        tokio::spawn(async move {
+            let mut stream = match accept(stream, Listener::Client).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Refusing client connection from {}: {}", peer_addr, e);
+                    return;
+                }
+            };
             // debug!("Received message from client!");
-            let req = match read_req(&mut stream) {
+            let req = match read_req(&mut stream).await {
                 Ok(req) => req,
                 Err(e) => {
                     error!("Failed reading message from client! {}", e);
@@ -163,115 +164,83 @@ pub fn client_listener(listener: TcpListener, state: Arc<RwLock<State>>, // Node
 
             match req {
                 ClientReq::Subscribe { client_id } => {
-                    let client: Client = Client {
-                        identity: client_id,
-                        message_queue: VecDeque::<&Vec<u8>>::new(),
-                    };
-                    debug!("Sub request from client {}", client.identity);
-
+                    debug!("Sub request from client {}", client_id);
 
-                    let mut client_list = client_list.write().unwrap();
-                    let write = client_list.insert(client_id, RwLock::from(client));
+                    // consuming starts with the next delivered message
+                    let write = {
+                        let mut state_ref = state.write().unwrap();
+                        let offset = state_ref.sequence.current();
+                        state_ref.offsets.insert(client_id, offset)
+                    };
                     match write {
-                        None => ok(&mut stream),
-                        Some(_) => ok_with_message(&mut stream, "Client exists, flushed queue")
+                        None => ok(&mut stream).await,
+                        Some(_) => ok_with_message(&mut stream, "Client exists, flushed queue").await
                     }
                 }
                 ClientReq::Unsubscribe { client_id } => {
                     debug!("Unsub request from client {}", client_id);
 
-                    let v = client_list.write().unwrap().remove(&client_id);
+                    let v = state.write().unwrap().offsets.remove(&client_id);
                     match v {
-                        None => err(&mut stream, "Client wasn't previously subscribed"),
-                        Some(_) => ok(&mut stream)
+                        None => err(&mut stream, "Client wasn't previously subscribed").await,
+                        Some(_) => ok(&mut stream).await
                     }
                 }
                 ClientReq::Poll { client_id } => {
-                    let client_list = client_list.read().unwrap();
-                    let client = match client_list.get(&client_id) {
-                        None => {
-                            err(&mut stream, "client not subscribed.");
-                            return;
-                        }
-                        Some(client) => { client }
+                    let message = {
+                        let mut state_ref = state.write().unwrap();
+                        let history = state_ref.history.clone();
+                        state_ref.offsets.get_mut(&client_id).map(|offset| {
+                            let message = history.after(*offset, 1).pop();
+                            if let Some(message) = &message {
+                                *offset = message.sequence;
+                            }
+                            message
+                        })
                     };
-                    let mut client = client.write().unwrap();
-
-                    let message = client.message_queue.pop_front();
 
                     match message {
                         None => {
+                            err(&mut stream, "client not subscribed.").await;
+                        }
+                        Some(None) => {
                             // write empty buffer
                             let res = ClientRes::Success {
                                 message: "Queue empty".to_string(),
                                 bytes: vec![],
                             };
-                            write_res(&mut stream, res);
+                            write_res(&mut stream, res).await;
                         }
-                        Some(message) => {
+                        Some(Some(message)) => {
                             let res = ClientRes::Success {
                                 message: "Message:".to_string(),
-                                bytes: message.to_vec(),
+                                bytes: message.message,
                             };
-                            write_res(&mut stream, res)
+                            write_res(&mut stream, res).await
                         }
                     }
                 }
                 ClientReq::LongPoll { client_id: _ } => {}
                 ClientReq::Publish { client_id, message } => {
                     debug!("Publishing message from client {} with size {}", client_id, message.len());
-                    let (req, rel) = ResourceRequest::generate(message);
-                    let key = rel.shorthand;
-
-                    let client = semaphore.create_task(req.timestamp);
-
-                    // Place REQUEST on local queue
-                    let mut pledge_queue = pledge_queue.lock().unwrap();
-                    pledge_queue.push(req);
-                    drop(pledge_queue);
-
-                    // Place eventual RELEASE on KV store
-                    let mut messages = pending_messages.lock().unwrap();
-                    messages.insert(key, (rel, false));
-                    drop(messages);
-
-                    // debug!("Sleeping to simulate concurrent request!");
-                    // std::thread::sleep(Duration::from_secs(15));
 
-                    // Publish REQUEST
-                    let state_ref = state_ref.read().unwrap();
-                    let neighbours = &state_ref.get_neighbour_addrs();
-                    drop(state_ref);
-
-                    let result = pub_req(neighbours, req);
+                    if state.read().unwrap().mode == Mode::Shutdown {
+                        err(&mut stream, "Node is shutting down").await;
+                        return;
+                    }
 
-                    match result {
-                        TaskSignal::Success => {
-                            client.consume();
-                            let mut messages = pending_messages.lock().unwrap();
-                            messages.entry(key).and_modify(|x| x.1 = true);
-                            debug!("Resource REQUEST acknowledged!");
-                        }
-                        _ => {
-                            error!("Resource REQUEST failed!");
-                        }
+                    match engine.publish(message).await {
+                        Ok(()) => ok(&mut stream).await,
+                        Err(e @ Error::NoQuorum) => err(&mut stream, &e.to_string()).await,
+                        Err(Error::Protocol { code, .. }) => err(&mut stream, &format!("Publish refused by the cluster: {}", code)).await,
+                        Err(e) => err(&mut stream, &format!("Publish failed: {}", e)).await,
                     }
-                    // ack client
-                    ok(&mut stream);
                 }
                 ClientReq::WaitUntilClear { client_id: _ } => {
-                    loop {
-                        let pledge_queue = pledge_queue.lock().unwrap();
-                        if pledge_queue.len() == 0 {
-                            ok(&mut stream);
-                            return
-                        } else {
-                            drop(pledge_queue);
-                            std::thread::sleep(Duration::from_millis(50));
-                        }
-                    }
+                    engine.cleared().await;
+                    ok(&mut stream).await;
                 }
             }
-        })
+        });
     }
 }
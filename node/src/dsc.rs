@@ -1,94 +1,118 @@
-use std::sync::{Arc, RwLock, mpsc};
+use std::sync::{Arc, RwLock};
 use crate::state::{Mode, State, Node};
-use std::net::{TcpStream, SocketAddr};
+use std::net::SocketAddr;
 
-use crate::proto::{Type, ProtoParcel, Body};
-use std::sync::mpsc::{Sender, Receiver};
+use crate::proto::{ProtoParcel, Body, Cluster, Handshake, ProtoErrorKind, Type};
 use std::collections::HashSet;
-use rayon::prelude::*;
-use log::{error, info};
+use futures::future::join_all;
+use log::{error, info, warn};
+use uuid::Uuid;
 
-use crate::net::{write_parcel, read_parcel};
+use crate::net::{connect, write_parcel, read_reply};
+use crate::error::{Error, Result};
+use crate::req::ext_addr::detect_ext_addr;
 
 // Start discovery routine
-pub fn dsc(state: Arc<RwLock<State>>, neighbour_list: &Vec<SocketAddr>) {
+// Fails with `Error::Membership` if the cluster refused this node. Retrying won't change its mind and
+// joining the rest of it would split it.
+pub async fn dsc(state: Arc<RwLock<State>>, neighbour_list: &[SocketAddr]) -> Result<()> {
     // Skip discovery
     if neighbour_list.len() == 0 {
         let mut state = state.write().unwrap();
+        if state.cluster.id.is_none() {
+            // this node forms the cluster
+            state.cluster.id = Some(Uuid::new_v4());
+            info!("Formed cluster {}", state.cluster);
+        }
         state.change_mode(Mode::Wrk);
-        return;
+        return Ok(());
     }
 
     info!("Attempting to connect to {} hosts", neighbour_list.len());
 
-    let (sender, receiver): (Sender<Vec<Node>>, Receiver<Vec<Node>>) = mpsc::channel(); // make channel for responses
+    // the address isn't configured, the neighbours can tell what it is
+    let (external_addr, port) = {
+        let state = state.read().unwrap();
+        (state.external_addr, state.internal_addr.port())
+    };
+    if external_addr.is_none() {
+        match detect_ext_addr(neighbour_list, port).await {
+            Ok(addr) => {
+                info!("External address is {}", addr);
+                state.write().unwrap().external_addr = Some(addr);
+            }
+            Err(e) => warn!("No neighbour told this node its address, set node.external_addr: {}", e),
+        }
+    }
 
-    let state_ref = state.read().unwrap();
-    let node = state_ref.get_node_information();
-    drop(state_ref);
-    let req_parcel = ProtoParcel::dsc_req(node); // Use same object for serializing each request
+    let (node, cluster) = {
+        let state = state.read().unwrap();
+        (state.get_node_information(), state.cluster.clone())
+    };
+    let req_parcel = ProtoParcel::dsc_req(node, cluster.clone()); // Use same object for serializing each request
 
     // begin parallel scope
-    let neighbour_list = neighbour_list.as_slice();
-    neighbour_list.into_par_iter().for_each_with(sender, |s, addr| {
-        let _state_ref = state.clone();
-        discover(&addr, &req_parcel, s);
-    });
+    let results = join_all(neighbour_list.iter().map(|addr| discover(addr, &req_parcel, &cluster))).await;
     // end parallel scope
 
     let mut neighbours: HashSet<Node> = HashSet::new();
+    let mut cluster_id = None;
 
     // collect results
-    for nodes in receiver.iter() {
-        neighbours.extend(nodes);
+    for result in results {
+        match result {
+            Ok((nodes, cluster)) => {
+                neighbours.extend(nodes);
+                cluster_id = cluster_id.or(cluster.id);
+            }
+            Err(e @ Error::Membership(_)) => return Err(e),
+            Err(_) => {} // unreachable, the others will do
+        }
     }
     let mut state = state.write().unwrap(); // acquire write lock
-    for neighbour in neighbours {
+    if state.cluster.id.is_none() {
+        state.cluster.id = cluster_id;
+    }
+    let self_id = state.id;
+    for neighbour in neighbours.into_iter().filter(|node| node.id != self_id) {
         info!("Found {}:{}!", neighbour.name, neighbour.mode);
         state.add_neighbour(neighbour);
     }
     state.change_mode(Mode::Wrk);
+    Ok(())
 }
 
 // Request/response on same tcp stream
-// Writes result to state after acquiring write lock
-fn discover(host: &SocketAddr, req_parcel: &ProtoParcel, tx: &mut Sender<Vec<Node>>) {
+// Returns the responding node along with its neighbours and cluster, `Error::Membership` if it
+// refused this node
+async fn discover(host: &SocketAddr, req_parcel: &ProtoParcel, own_cluster: &Cluster) -> Result<(Vec<Node>, Cluster)> {
     info!("Connecting to {}", host);
-    let mut stream = match TcpStream::connect(host) {
-        Ok(stream) => stream,
-        Err(err) => {
-            error!("{}: {}", err, host);
-            return;
-        }
-    };
-
-    write_parcel(&mut stream, req_parcel);
+    let mut stream = connect(host).await.inspect_err(|err| error!("{}: {}", err, host))?;
+    write_parcel(&mut stream, req_parcel).await.inspect_err(|err| error!("{}: {}", err, host))?;
+    let res_parcel = read_reply(&mut stream).await.inspect_err(|e| error!("Invalid parcel! {}", e))?;
 
-    let res_parcel = match read_parcel(&mut stream) {
-        Ok(parcel) => parcel,
-        Err(e) => {
-            error!("Invalid parcel! {}", e);
-            return;
-        }
-    };
-
-    match res_parcel.parcel_type {
-        Type::DscRes => {
-            if let Body::DscRes { mut neighbours, mut self_id } = res_parcel.body {
-                self_id.external_addr = *host; // change hostname to the one the node was contacted on
-                neighbours.push(self_id);
-                tx.send(neighbours).unwrap();
-            } else {
-                error!("Body-header type mismatch!");
-                return;
+    match res_parcel.body {
+        Body::DscRes { mut neighbours, mut self_id, cluster, handshake } => {
+            if let Err(kind) = Handshake::local().negotiate(&handshake) {
+                error!("Can't talk to {}: {}", host, kind);
+                return Err(Error::Membership(kind.to_string()));
+            }
+            if !own_cluster.accepts(&cluster) {
+                error!("{} belongs to cluster {}, this is {}", host, cluster, own_cluster);
+                return Err(Error::Membership(ProtoErrorKind::ClusterMismatch.to_string()));
             }
+            self_id.external_addr = *host; // change hostname to the one the node was contacted on
+            neighbours.push(self_id);
+            Ok((neighbours, cluster))
         }
 
-        Type::ProtoError => {}
-        _ => {
-            error!("Unexpected response type to discovery request, {}", res_parcel.parcel_type);
-            return;
+        Body::Error { code, detail, .. } => {
+            error!("{} refused discovery: {}, {}", host, code, detail);
+            Err(Error::Membership(detail))
+        }
+        body => {
+            error!("Unexpected response type to discovery request, {}", body.parcel_type());
+            Err(Error::unexpected(Type::DscRes, body.parcel_type()))
         }
     }
 }
-
@@ -0,0 +1,239 @@
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::Notify;
+
+use log::{debug, error, info, warn};
+
+use crate::batch::RequestBatcher;
+use crate::engine::OrderingEngine;
+use crate::error::{Error, Result};
+use crate::proto::{Body, ProtoError, ProtoParcel, ReleaseMode, ResourceRelease, ResourceRequest};
+use crate::semaphore::OrdSemaphore;
+use crate::state::State;
+use crate::wrk::{wrk, Receivers};
+use uuid::Uuid;
+
+/// The all-to-all lock protocol.
+///
+/// Every publish is a resource request that each neighbour queues and acknowledges. Each node
+/// keeps a priority queue of requests ordered by timestamp and delivers a message once its request
+/// reaches the head of the queue and its owner releases it.
+pub struct LamportEngine {
+    state: Arc<RwLock<State>>,
+    resource_queue: Arc<Mutex<BinaryHeap<ResourceRequest>>>,
+    semaphore: Arc<OrdSemaphore<DateTime<Utc>>>,
+    pending_messages: Arc<Mutex<HashMap<u64, (ResourceRelease, bool)>>>,
+    batcher: RequestBatcher,
+    release_mode: ReleaseMode,
+    // a request left the queue or a release went out
+    progress: Arc<Notify>,
+
+    releases: UnboundedSender<ResourceRelease>,
+    queue_changed: UnboundedSender<()>,
+    acks: UnboundedSender<u64>,
+    receivers: tokio::sync::Mutex<Receivers>,
+}
+
+impl LamportEngine {
+    /// Must be called from within the tokio runtime.
+    pub fn new(state: Arc<RwLock<State>>, batch_linger: Duration, release_mode: ReleaseMode) -> LamportEngine {
+        let (releases, release_receiver) = mpsc::unbounded_channel();
+        let (queue_changed, queue_receiver) = mpsc::unbounded_channel();
+        let (acks, ack_receiver) = mpsc::unbounded_channel();
+
+        LamportEngine {
+            batcher: RequestBatcher::new(state.clone(), batch_linger, releases.clone()),
+            release_mode,
+            state,
+            resource_queue: Arc::new(Mutex::new(BinaryHeap::new())),
+            semaphore: Arc::new(OrdSemaphore::new()),
+            pending_messages: Arc::new(Mutex::new(HashMap::new())),
+            progress: Arc::new(Notify::new()),
+            releases,
+            queue_changed,
+            acks,
+            receivers: tokio::sync::Mutex::new(Receivers {
+                releases: release_receiver,
+                queue_changed: queue_receiver,
+                acks: ack_receiver,
+            }),
+        }
+    }
+
+    // Whether one of our own requests before `timestamp` has yet to leave the critical section.
+    fn own_request_before(&self, self_id: Uuid, timestamp: &DateTime<Utc>) -> bool {
+        self.resource_queue.lock().unwrap().iter().any(|req| req.owner == self_id && req.timestamp < *timestamp)
+    }
+
+    // With deferred releases a grant also waits for our earlier requests to leave the critical section,
+    // so that it carries their releases and the requester can go ahead as soon as it has every grant.
+    async fn wait_for_exits(&self, timestamp: &DateTime<Utc>) {
+        let (self_id, outbox) = {
+            let state_ref = self.state.read().unwrap();
+            (state_ref.id, state_ref.outbox.clone())
+        };
+        outbox.wait_for_exits(|| self.own_request_before(self_id, timestamp)).await;
+    }
+
+    // Acknowledges the first `accepted` requests of parcel `id`. With deferred releases this is a grant
+    // carrying everything held back for the sender.
+    fn acknowledge(&self, sender_id: Uuid, id: u64, accepted: usize, batch: bool) -> ProtoParcel {
+        match self.release_mode {
+            ReleaseMode::Deferred => {
+                let resource_releases = self.state.read().unwrap().outbox.take(sender_id);
+                ProtoParcel::grant(id, accepted, resource_releases)
+            }
+            ReleaseMode::Broadcast if batch => ProtoParcel::batch_ack(id, accepted),
+            ReleaseMode::Broadcast => ProtoParcel::ack(id),
+        }
+    }
+}
+
+#[async_trait]
+impl OrderingEngine for LamportEngine {
+    async fn publish(&self, message: Vec<u8>) -> Result<()> {
+        // refuse before anything is queued, a request that can't be acknowledged would block the queue
+        if !self.state.read().unwrap().has_quorum() {
+            warn!("Refusing publish without a majority of the cluster");
+            return Err(Error::NoQuorum);
+        }
+
+        let (req, rel) = ResourceRequest::generate(message);
+        let key = rel.shorthand;
+
+        let client = self.semaphore.create_task(req.timestamp);
+
+        // Place REQUEST on local queue
+        self.resource_queue.lock().unwrap().push(req);
+        self.queue_changed.send(()).unwrap();
+
+        // Place eventual RELEASE on KV store
+        self.pending_messages.lock().unwrap().insert(key, (rel, false));
+
+        // Publish REQUEST
+        let result = self.batcher.submit(req).await;
+
+        match result {
+            Ok(()) => {
+                client.consume();
+                self.pending_messages.lock().unwrap().entry(key).and_modify(|x| x.1 = true);
+                self.acks.send(key).unwrap();
+                debug!("Resource REQUEST acknowledged!");
+            }
+            _ => {
+                error!("Resource REQUEST failed!");
+            }
+        }
+        result
+    }
+
+    async fn handle(&self, parcel: ProtoParcel) -> Option<ProtoParcel> {
+        match parcel.body {
+            Body::ResourceRequest { resource_request } => {
+                info!("Processing Resource Request with id {} from node {}", parcel.id, parcel.sender_id);
+
+                self.semaphore.wait_until_async(&resource_request.timestamp).await;
+                if self.release_mode == ReleaseMode::Deferred {
+                    self.wait_for_exits(&resource_request.timestamp).await;
+                }
+
+                self.resource_queue.lock().unwrap().push(resource_request);
+                self.queue_changed.send(()).unwrap();
+                Some(self.acknowledge(parcel.sender_id, parcel.id, 1, false))
+            }
+            Body::ResourceRelease { resource_release } => {
+                info!("Processing Resource Release with hash {} from node {}", resource_release.shorthand, parcel.sender_id);
+
+                self.releases.send(resource_release).unwrap();
+                Some(ProtoParcel::ack(parcel.id))
+            }
+            Body::ResourceRequestBatch { resource_requests } => {
+                info!("Processing {} Resource Requests with id {} from node {}", resource_requests.len(), parcel.id, parcel.sender_id);
+
+                // Requests arrive in timestamp order. The first one waits like a single request would,
+                // the rest are only taken while that needs no waiting. Otherwise the acknowledgement of
+                // the whole batch could be held up by a local request which itself waits on the sender.
+                let deferred = self.release_mode == ReleaseMode::Deferred;
+                let self_id = self.state.read().unwrap().id;
+                let mut accepted = 0;
+                for resource_request in resource_requests {
+                    if accepted == 0 {
+                        self.semaphore.wait_until_async(&resource_request.timestamp).await;
+                        if deferred {
+                            self.wait_for_exits(&resource_request.timestamp).await;
+                        }
+                    } else if !self.semaphore.is_clear(&resource_request.timestamp) ||
+                        (deferred && self.own_request_before(self_id, &resource_request.timestamp)) {
+                        break;
+                    }
+
+                    self.resource_queue.lock().unwrap().push(resource_request);
+                    accepted += 1;
+                }
+                self.queue_changed.send(()).unwrap();
+                Some(self.acknowledge(parcel.sender_id, parcel.id, accepted, true))
+            }
+            Body::ResourceReleaseBatch { resource_releases } => {
+                info!("Processing {} Resource Releases from node {}", resource_releases.len(), parcel.sender_id);
+
+                for resource_release in resource_releases {
+                    self.releases.send(resource_release).unwrap();
+                }
+                Some(ProtoParcel::ack(parcel.id))
+            }
+            _ => {
+                error!("Unexpected {} for the lamport engine", parcel.parcel_type());
+                Some(ProtoParcel::error(ProtoError::UnexpectedType, format!("{} isn't used by the lamport engine", parcel.parcel_type()), parcel.id))
+            }
+        }
+    }
+
+    async fn run(&self) {
+        let mut receivers = self.receivers.lock().await;
+
+        wrk(self.state.clone(), self.resource_queue.clone(), &mut receivers, self.pending_messages.clone(), self.progress.clone(), self.release_mode).await;
+    }
+
+    fn is_clear(&self) -> bool {
+        let outbox = self.state.read().unwrap().outbox.clone();
+        self.resource_queue.lock().unwrap().is_empty() && self.pending_messages.lock().unwrap().is_empty() && outbox.is_empty()
+    }
+
+    async fn cleared(&self) {
+        let outbox = self.state.read().unwrap().outbox.clone();
+        loop {
+            let progressed = self.progress.notified();
+            let taken = outbox.taken();
+            tokio::pin!(progressed, taken);
+            // register before checking so nothing that clears the node is missed
+            progressed.as_mut().enable();
+            taken.as_mut().enable();
+
+            if self.is_clear() {
+                return;
+            }
+            tokio::select! {
+                _ = progressed => {}
+                _ = taken => {}
+            }
+        }
+    }
+
+    // A node leaves once its own requests are through, whatever is left of them would never be released
+    fn forget(&self, id: Uuid) {
+        let mut q_lock = self.resource_queue.lock().unwrap();
+        let queued = q_lock.len();
+        q_lock.retain(|req| req.owner != id);
+        if q_lock.len() < queued {
+            warn!("Dropped {} requests of node {} that left", queued - q_lock.len(), id);
+        }
+        drop(q_lock);
+        let _ = self.queue_changed.send(());
+        self.progress.notify_waiters();
+    }
+}
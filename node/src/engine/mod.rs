@@ -0,0 +1,35 @@
+use async_trait::async_trait;
+
+use crate::error::Result;
+use crate::proto::ProtoParcel;
+use uuid::Uuid;
+
+pub mod lamport;
+pub mod raft;
+mod raft_store;
+
+/// Decides the order in which published messages are delivered across the cluster.
+///
+/// The client listener hands publishes to the engine, the cluster listener hands it every parcel
+/// of its own protocol and the main loop runs it for as long as the node is working.
+#[async_trait]
+pub trait OrderingEngine: Send + Sync {
+    /// Orders `message` with the rest of the cluster. Resolves once its place is settled, or with
+    /// why it couldn't be.
+    async fn publish(&self, message: Vec<u8>) -> Result<()>;
+
+    /// Handles a parcel of the engine's protocol, returning the response if there is one.
+    async fn handle(&self, parcel: ProtoParcel) -> Option<ProtoParcel>;
+
+    /// Drives the engine while the node is in `Mode::Wrk`.
+    async fn run(&self);
+
+    /// Whether nothing published on this node is still waiting to be delivered.
+    fn is_clear(&self) -> bool;
+
+    /// Resolves once `is_clear` holds.
+    async fn cleared(&self);
+
+    /// Drops whatever is held for node `id`, which has left the cluster.
+    fn forget(&self, _id: Uuid) {}
+}
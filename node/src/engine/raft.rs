@@ -0,0 +1,784 @@
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::future::{self, join_all};
+use futures::stream::{FuturesUnordered, StreamExt};
+use rand::Rng;
+use tokio::sync::Notify;
+
+use log::{debug, error, info, warn};
+
+use crate::engine::OrderingEngine;
+use crate::engine::raft_store::RaftStore;
+use crate::error::{Error, Result};
+use crate::net::is_acked;
+use crate::history::History;
+use crate::proto::{Body, LogEntry, MessageWrapper, ProtoError, ProtoParcel};
+use crate::req::push_state::push_state;
+use crate::state::{Mode, Sequence, State, quorum};
+use uuid::Uuid;
+
+// Upper bound on entries carried by a single AppendReq
+const MAX_ENTRIES: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// Replicated log and election state, only ever locked for short sections. Changes to the term, vote
+// and log are written through to the store before they take effect.
+struct Raft {
+    term: u64,
+    voted_for: Option<Uuid>,
+    role: Role,
+    leader: Option<Uuid>,
+
+    // log[i] holds the entry at index i + 1, index 0 stands for the empty log
+    log: Vec<LogEntry>,
+    store: RaftStore,
+    // configuration in effect, the one of the last configuration entry in the log whether committed
+    // or not, and the index of that entry
+    members: Vec<Uuid>,
+    config_index: u64,
+    commit_index: u64,
+    last_applied: u64,
+    // every node applies the same log, so counting applied messages numbers them alike everywhere
+    sequence: Arc<Sequence>,
+    history: Arc<History>,
+
+    // Leader only, per follower: next entry to send, highest entry known to be stored and
+    // when it last answered.
+    next_index: HashMap<Uuid, u64>,
+    match_index: HashMap<Uuid, u64>,
+    last_ack: HashMap<Uuid, Instant>,
+
+    // last time a leader or candidate was heard from
+    last_heard: Instant,
+}
+
+impl Raft {
+    fn last_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            _ => self.log[index as usize - 1].term,
+        }
+    }
+
+    // Every member of the configuration but `self_id`
+    fn peers(&self, self_id: Uuid) -> Vec<Uuid> {
+        self.members.iter().filter(|id| **id != self_id).copied().collect()
+    }
+
+    fn vote(&mut self, term: u64, voted_for: Option<Uuid>) -> io::Result<()> {
+        self.store.save_vote(term, voted_for)?;
+        self.term = term;
+        self.voted_for = voted_for;
+        Ok(())
+    }
+
+    // Follows whoever leads `term`, forgetting the vote if the term is new. Stops leading even if the
+    // new term can't be stored.
+    fn step_down(&mut self, term: u64) -> io::Result<()> {
+        self.role = Role::Follower;
+        if term > self.term {
+            self.vote(term, None)?;
+            self.leader = None;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, entries: Vec<LogEntry>) -> io::Result<()> {
+        self.store.append(&entries)?;
+        self.log.extend(entries);
+        self.reconfigure();
+        Ok(())
+    }
+
+    // Drops the entries after the first `len`, which conflict with the leader's.
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.store.truncate(len as usize)?;
+        self.log.truncate(len as usize);
+        self.reconfigure();
+        Ok(())
+    }
+
+    // Takes up the last configuration in the log. A node that joined and wasn't added by a leader
+    // yet has none.
+    fn reconfigure(&mut self) {
+        let last = self.log.iter().enumerate().rev().find_map(|(i, entry)| entry.members.as_ref().map(|members| (i as u64 + 1, members)));
+        let (config_index, members) = match last {
+            Some((index, members)) => (index, members.clone()),
+            None => (0, vec![]),
+        };
+        if members != self.members {
+            debug!("Configuration at entry {} has {} members", config_index, members.len());
+        }
+        self.config_index = config_index;
+        self.members = members;
+    }
+
+    fn become_leader(&mut self, self_id: Uuid) -> io::Result<()> {
+        // committing an entry of its own term also commits whatever earlier terms left behind
+        self.append(vec![LogEntry { term: self.term, owner: self_id, message: None, members: None }])?;
+
+        info!("Elected leader for term {}", self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self_id);
+        self.next_index.clear();
+        self.match_index.clear();
+        self.last_ack.clear();
+        let peers = self.peers(self_id);
+        self.track(&peers);
+        Ok(())
+    }
+
+    // Starts replicating to any of `peers` not tracked yet.
+    fn track(&mut self, peers: &[Uuid]) {
+        let next = self.last_index() + 1;
+        let now = Instant::now();
+        for id in peers {
+            self.next_index.entry(*id).or_insert(next);
+            self.match_index.entry(*id).or_insert(0);
+            self.last_ack.entry(*id).or_insert(now);
+        }
+    }
+
+    // Moves the commit index to the highest entry stored on a majority of the configuration. Only
+    // entries of the current term are committed by counting, earlier ones are committed along with them.
+    fn advance_commit(&mut self, self_id: Uuid) -> bool {
+        if self.members.is_empty() {
+            return false;
+        }
+        let mut stored: Vec<u64> = self.members.iter()
+            .map(|id| if *id == self_id { self.last_index() } else { self.match_index.get(id).copied().unwrap_or(0) })
+            .collect();
+        stored.sort_unstable_by(|a, b| b.cmp(a));
+
+        let index = stored[quorum(stored.len()) - 1];
+        if index > self.commit_index && self.term_at(index) == self.term {
+            self.commit_index = index;
+            self.apply();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn apply(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[self.last_applied as usize - 1];
+            if let Some(message) = &entry.message {
+                let sequence = self.sequence.next();
+                info!("Committed entry {} from node {} seq {} message {}", self.last_applied, entry.owner, sequence, String::from_utf8_lossy(message));
+                self.history.record(MessageWrapper { message: message.clone(), sequence, receiver_mask: 0 });
+            }
+        }
+    }
+}
+
+/// A leader based, Raft-style replicated log.
+///
+/// Publishes are appended to the leader's log and delivered once a majority of the configured
+/// membership stores them, so a minority of slow or dead nodes doesn't hold the cluster back.
+/// Followers hand their publishes to the leader. A leader that can't reach a majority for an
+/// election timeout steps down, failing the publishes it was holding.
+///
+/// The term, vote and log are kept in the data directory. The membership is part of the log: the
+/// leader follows nodes joining and leaving the cluster one node at a time, each change committed
+/// before the next is made, so the majorities of consecutive configurations always overlap.
+pub struct RaftEngine {
+    state: Arc<RwLock<State>>,
+    raft: Mutex<Raft>,
+
+    // new entries to replicate
+    appended: Notify,
+    // commit index moved or leadership changed
+    committed: Notify,
+    // local publishes not settled yet
+    in_flight: AtomicUsize,
+    // the last local publish in flight settled
+    drained: Notify,
+
+    election_timeout: Duration,
+    heartbeat: Duration,
+}
+
+// What a replicator does after an AppendReq
+enum Step {
+    Stop,
+    Send,
+    Idle,
+    Retry,
+}
+
+impl RaftEngine {
+    /// Loads the term, vote and log kept in `data_dir`.
+    pub fn open(state: Arc<RwLock<State>>, data_dir: &Path, election_timeout: Duration, heartbeat: Duration) -> io::Result<RaftEngine> {
+        let (sequence, history) = {
+            let state_ref = state.read().unwrap();
+            (state_ref.sequence.clone(), state_ref.history.clone())
+        };
+        let (store, term, voted_for, log) = RaftStore::open(data_dir)?;
+        let mut raft = Raft {
+            term,
+            voted_for,
+            role: Role::Follower,
+            leader: None,
+            log,
+            store,
+            members: vec![],
+            config_index: 0,
+            commit_index: 0,
+            last_applied: 0,
+            sequence,
+            history,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            last_ack: HashMap::new(),
+            last_heard: Instant::now(),
+        };
+        raft.reconfigure();
+
+        Ok(RaftEngine {
+            state,
+            raft: Mutex::new(raft),
+            appended: Notify::new(),
+            committed: Notify::new(),
+            in_flight: AtomicUsize::new(0),
+            drained: Notify::new(),
+            election_timeout,
+            heartbeat,
+        })
+    }
+
+    /// Starts the log of a new cluster with a configuration of this node alone. Nodes that join
+    /// later are added by the leader. Does nothing if there already is a log.
+    pub fn bootstrap(&self) -> io::Result<()> {
+        let self_id = self.state.read().unwrap().id;
+        let mut raft = self.raft.lock().unwrap();
+        if raft.last_index() == 0 {
+            info!("Starting a new log");
+            raft.append(vec![LogEntry { term: 0, owner: self_id, message: None, members: Some(vec![self_id]) }])?;
+        }
+        Ok(())
+    }
+
+    // Every member of the configuration but this one, reachable or not
+    fn peers(&self) -> (Uuid, Vec<Uuid>) {
+        let self_id = self.state.read().unwrap().id;
+        let mut peers = self.raft.lock().unwrap().peers(self_id);
+        peers.sort_unstable();
+        (self_id, peers)
+    }
+
+    // The nodes a leader replicates to: the other members, and working neighbours outside the
+    // configuration catching up before they are added to it
+    fn replicas(&self) -> (Uuid, Vec<Uuid>) {
+        let (self_id, mut replicas) = self.peers();
+        let working = self.state.read().unwrap().get_active_neighbour_keys();
+        for id in working {
+            if !replicas.contains(&id) {
+                replicas.push(id);
+            }
+        }
+        replicas.sort_unstable();
+        (self_id, replicas)
+    }
+
+    async fn call(&self, id: Uuid, parcel: ProtoParcel) -> Option<ProtoParcel> {
+        let conn = {
+            let state_ref = self.state.read().unwrap();
+            state_ref.pool.get(state_ref.neighbours.get(&id)?)
+        };
+
+        match tokio::time::timeout(self.election_timeout, conn.request(parcel)).await {
+            Ok(Ok(parcel)) => Some(parcel),
+            Ok(Err(e)) => {
+                debug!("{}: {}", e, conn.addr());
+                None
+            }
+            Err(_) => {
+                debug!("Timed out waiting on {}", conn.addr());
+                None
+            }
+        }
+    }
+
+    // Appends `message` as leader and waits for it to be committed.
+    async fn append(&self, message: Vec<u8>) -> Result<()> {
+        let (index, term) = {
+            let self_id = self.state.read().unwrap().id;
+            let mut raft = self.raft.lock().unwrap();
+            if raft.role != Role::Leader {
+                return Err(Error::Ordering("not the leader".to_string()));
+            }
+            let term = raft.term;
+            raft.append(vec![LogEntry { term, owner: self_id, message: Some(message), members: None }])?;
+            if raft.advance_commit(self_id) {
+                self.committed.notify_waiters();
+            }
+            (raft.last_index(), term)
+        };
+        self.appended.notify_waiters();
+
+        loop {
+            let committed = self.committed.notified();
+            tokio::pin!(committed);
+            committed.as_mut().enable(); // register before checking so no commit is missed
+
+            {
+                let raft = self.raft.lock().unwrap();
+                if raft.last_index() < index || raft.term_at(index) != term {
+                    return Err(Error::Ordering("overwritten by a later leader".to_string()));
+                }
+                if raft.commit_index >= index {
+                    return Ok(());
+                }
+                if raft.role != Role::Leader || raft.term != term {
+                    // whether it makes it is up to the next leader
+                    return Err(Error::Ordering("lost leadership before it was committed".to_string()));
+                }
+            }
+            committed.await;
+        }
+    }
+
+    async fn follow(&self) {
+        let timeout = self.election_timeout + self.election_timeout.mul_f64(rand::thread_rng().gen::<f64>());
+
+        loop {
+            let deadline = self.raft.lock().unwrap().last_heard + timeout;
+            if Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+
+        self.elect().await;
+    }
+
+    async fn elect(&self) {
+        let (self_id, peers) = self.peers();
+        let (term, req, needed) = {
+            let mut raft = self.raft.lock().unwrap();
+            raft.last_heard = Instant::now();
+            if !raft.members.contains(&self_id) {
+                // a joining node waits for the leader to add it
+                debug!("Not a member of the configuration, not starting an election");
+                return;
+            }
+            let term = raft.term + 1;
+            if let Err(e) = raft.vote(term, Some(self_id)) {
+                error!("Can't start an election for term {}, {}", term, e);
+                return;
+            }
+            raft.role = Role::Candidate;
+            raft.leader = None;
+            (term, ProtoParcel::vote_req(term, raft.last_index(), raft.term_at(raft.last_index())), quorum(raft.members.len()))
+        };
+        info!("Starting election for term {}", term);
+
+        let mut votes = 1;
+
+        // begin parallel scope
+        let mut replies: FuturesUnordered<_> = peers.iter().map(|id| self.call(*id, req.clone())).collect();
+        loop {
+            {
+                let mut raft = self.raft.lock().unwrap();
+                if raft.role != Role::Candidate || raft.term != term {
+                    return;
+                }
+                if votes >= needed {
+                    if let Err(e) = raft.become_leader(self_id) {
+                        error!("Can't lead term {}, {}", term, e);
+                        raft.role = Role::Follower;
+                        return;
+                    }
+                    if raft.advance_commit(self_id) {
+                        self.committed.notify_waiters();
+                    }
+                    return;
+                }
+            }
+
+            match replies.next().await {
+                Some(Some(ProtoParcel { body: Body::VoteRes { term: their_term, granted }, .. })) => {
+                    let mut raft = self.raft.lock().unwrap();
+                    if their_term > raft.term {
+                        if let Err(e) = raft.step_down(their_term) {
+                            error!("Can't store term {}, {}", their_term, e);
+                        }
+                    } else if granted {
+                        votes += 1;
+                    }
+                }
+                Some(_) => {}
+                None => return, // split vote, try again after another timeout
+            }
+        }
+        // end parallel scope
+    }
+
+    async fn lead(&self, term: u64) {
+        let (self_id, replicas) = self.replicas();
+        self.raft.lock().unwrap().track(&replicas);
+
+        let replicate = async {
+            join_all(replicas.iter().map(|id| self.replicate(*id, term, self_id))).await;
+            future::pending::<()>().await
+        };
+
+        let watch = async {
+            loop {
+                tokio::time::sleep(self.heartbeat).await;
+                self.change_membership(term);
+                if !self.still_leading(term, &replicas) {
+                    return;
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = replicate => {}
+            _ = watch => {}
+        }
+    }
+
+    // Moves the configuration one node closer to the membership of the cluster, once the previous
+    // change and an entry of this term are committed. Changing a single node at a time means any
+    // majority of the old configuration overlaps any majority of the new one, so the two can't elect
+    // a leader each. A node is only added once it stored everything committed, so a node that
+    // can't be reached never becomes a member the majority depends on. Nodes that time out stay
+    // members, only those that left are removed.
+    fn change_membership(&self, term: u64) {
+        let (self_id, wanted) = {
+            let state_ref = self.state.read().unwrap();
+            (state_ref.id, state_ref.get_neighbour_keys())
+        };
+        let mut raft = self.raft.lock().unwrap();
+        if raft.role != Role::Leader || raft.term != term || raft.config_index > raft.commit_index || raft.term_at(raft.commit_index) != term {
+            return;
+        }
+
+        let mut members = raft.members.clone();
+        let caught_up = |id: &Uuid| raft.match_index.get(id).is_some_and(|index| *index >= raft.commit_index);
+        if let Some(id) = wanted.iter().find(|id| !members.contains(id) && caught_up(id)) {
+            info!("Adding node {} to the configuration", id);
+            members.push(*id);
+        } else if let Some(i) = members.iter().position(|id| *id != self_id && !wanted.contains(id)) {
+            info!("Removing node {} from the configuration", members[i]);
+            members.remove(i);
+        } else {
+            return;
+        }
+
+        if let Err(e) = raft.append(vec![LogEntry { term, owner: self_id, message: None, members: Some(members) }]) {
+            error!("Can't change the configuration, {}", e);
+            return;
+        }
+        if raft.advance_commit(self_id) {
+            self.committed.notify_waiters();
+        }
+        drop(raft);
+        self.appended.notify_waiters();
+    }
+
+    // Whether to keep leading with the current replicators. A leader that hasn't heard from a
+    // majority within an election timeout steps down, since the majority has likely moved on.
+    fn still_leading(&self, term: u64, replicas: &[Uuid]) -> bool {
+        let peers = self.peers().1;
+        if self.replicas().1 != replicas {
+            return false; // membership changed, restart replication
+        }
+
+        let mut raft = self.raft.lock().unwrap();
+        if raft.role != Role::Leader || raft.term != term {
+            return false;
+        }
+
+        let now = Instant::now();
+        let reachable = 1 + peers.iter()
+            .filter(|id| raft.last_ack.get(id).is_some_and(|at| now.duration_since(*at) < self.election_timeout))
+            .count();
+        if reachable < quorum(raft.members.len()) {
+            warn!("Lost contact with a majority, stepping down from term {}", term);
+            raft.role = Role::Follower;
+            raft.leader = None;
+            self.committed.notify_waiters();
+            return false;
+        }
+        true
+    }
+
+    // Keeps follower `id` up to date for as long as this node leads `term`.
+    async fn replicate(&self, id: Uuid, term: u64, self_id: Uuid) {
+        loop {
+            let appended = self.appended.notified();
+            tokio::pin!(appended);
+            appended.as_mut().enable();
+
+            let req = {
+                let raft = self.raft.lock().unwrap();
+                if raft.role != Role::Leader || raft.term != term {
+                    return;
+                }
+                let prev = raft.next_index[&id] - 1;
+                let end = min(raft.log.len(), prev as usize + MAX_ENTRIES);
+                let entries = raft.log[prev as usize..end].to_vec();
+                ProtoParcel::append_req(term, prev, raft.term_at(prev), entries, raft.commit_index)
+            };
+
+            let step = match self.call(id, req).await {
+                Some(ProtoParcel { body: Body::AppendRes { term: their_term, success, match_index }, .. }) => {
+                    let mut raft = self.raft.lock().unwrap();
+                    if their_term > raft.term {
+                        if let Err(e) = raft.step_down(their_term) {
+                            error!("Can't store term {}, {}", their_term, e);
+                        }
+                        self.committed.notify_waiters();
+                        Step::Stop
+                    } else if raft.role != Role::Leader || raft.term != term {
+                        Step::Stop
+                    } else {
+                        raft.last_ack.insert(id, Instant::now());
+                        if success {
+                            let matched = max(raft.match_index[&id], match_index);
+                            raft.match_index.insert(id, matched);
+                            raft.next_index.insert(id, matched + 1);
+                            if raft.advance_commit(self_id) {
+                                self.committed.notify_waiters();
+                            }
+                        } else {
+                            let next = max(1, min(raft.next_index[&id] - 1, match_index + 1));
+                            raft.next_index.insert(id, next);
+                        }
+
+                        if !success || raft.next_index[&id] <= raft.last_index() { Step::Send } else { Step::Idle }
+                    }
+                }
+                _ => Step::Retry,
+            };
+
+            match step {
+                Step::Stop => return,
+                Step::Send => {}
+                // unreachable, don't hammer it
+                Step::Retry => tokio::time::sleep(self.heartbeat).await,
+                Step::Idle => {
+                    tokio::select! {
+                        _ = &mut appended => {}
+                        _ = tokio::time::sleep(self.heartbeat) => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Answers a request that can't be served because the raft state couldn't be stored.
+fn unstored(e: io::Error, in_reply_to: u64) -> ProtoParcel {
+    error!("Can't store the raft state, {}", e);
+    ProtoParcel::error(ProtoError::Unavailable, format!("can't store the raft state, {}", e), in_reply_to)
+}
+
+// Counts a local publish as in flight for as long as it is alive
+struct InFlight<'a>(&'a AtomicUsize, &'a Notify);
+
+impl<'a> InFlight<'a> {
+    fn new(count: &'a AtomicUsize, drained: &'a Notify) -> InFlight<'a> {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count, drained)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.0.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.1.notify_waiters();
+        }
+    }
+}
+
+#[async_trait]
+impl OrderingEngine for RaftEngine {
+    async fn publish(&self, message: Vec<u8>) -> Result<()> {
+        let _in_flight = InFlight::new(&self.in_flight, &self.drained);
+
+        let (self_id, has_quorum) = {
+            let state_ref = self.state.read().unwrap();
+            (state_ref.id, state_ref.has_quorum())
+        };
+        if !has_quorum {
+            warn!("Refusing publish without a majority of the cluster");
+            return Err(Error::NoQuorum);
+        }
+        let leader = self.raft.lock().unwrap().leader;
+
+        match leader {
+            Some(leader) if leader == self_id => self.append(message).await,
+            Some(leader) => {
+                let req = ProtoParcel::forward(message);
+                let req_id = req.id;
+                let conn = {
+                    let state_ref = self.state.read().unwrap();
+                    state_ref.neighbours.get(&leader).map(|node| state_ref.pool.get(node))
+                };
+                match conn {
+                    Some(conn) => match conn.request(req).await {
+                        Ok(res) => is_acked(res, req_id),
+                        Err(e) => {
+                            warn!("{}: {}", e, conn.addr());
+                            Err(e)
+                        }
+                    },
+                    None => Err(Error::Membership(format!("leader {} isn't a neighbour", leader))),
+                }
+            }
+            None => {
+                warn!("No leader to publish to");
+                Err(Error::Ordering("no leader to publish to".to_string()))
+            }
+        }
+    }
+
+    async fn handle(&self, parcel: ProtoParcel) -> Option<ProtoParcel> {
+        let sender = parcel.sender_id;
+
+        match parcel.body {
+            Body::VoteReq { term, last_log_index, last_log_term } => {
+                let mut raft = self.raft.lock().unwrap();
+                if term > raft.term {
+                    if raft.role == Role::Leader {
+                        self.committed.notify_waiters();
+                    }
+                    if let Err(e) = raft.step_down(term) {
+                        return Some(unstored(e, parcel.id));
+                    }
+                }
+
+                // only vote for candidates whose log holds everything ours does
+                let up_to_date = (last_log_term, last_log_index) >= (raft.term_at(raft.last_index()), raft.last_index());
+                let granted = term == raft.term && up_to_date && raft.voted_for.is_none_or(|id| id == sender);
+                if granted {
+                    debug!("Voting for node {} in term {}", sender, term);
+                    // the vote is on disk before the candidate hears of it
+                    if let Err(e) = raft.vote(term, Some(sender)) {
+                        return Some(unstored(e, parcel.id));
+                    }
+                    raft.last_heard = Instant::now();
+                }
+                Some(ProtoParcel::vote_res(raft.term, granted))
+            }
+            Body::AppendReq { term, prev_log_index, prev_log_term, entries, leader_commit } => {
+                let mut raft = self.raft.lock().unwrap();
+                if term < raft.term {
+                    return Some(ProtoParcel::append_res(raft.term, false, 0));
+                }
+                if raft.role == Role::Leader {
+                    self.committed.notify_waiters();
+                }
+                if let Err(e) = raft.step_down(term) {
+                    return Some(unstored(e, parcel.id));
+                }
+                if raft.leader != Some(sender) {
+                    info!("Following node {} in term {}", sender, term);
+                    raft.leader = Some(sender);
+                }
+                raft.last_heard = Instant::now();
+
+                if prev_log_index > raft.last_index() || raft.term_at(prev_log_index) != prev_log_term {
+                    let retry_from = min(raft.last_index(), prev_log_index.saturating_sub(1));
+                    return Some(ProtoParcel::append_res(raft.term, false, retry_from));
+                }
+
+                // the entries are on disk before the leader counts them as stored
+                let mut index = prev_log_index;
+                let mut new = vec![];
+                for entry in entries {
+                    index += 1;
+                    if index <= raft.last_index() {
+                        if raft.term_at(index) == entry.term {
+                            continue;
+                        }
+                        if let Err(e) = raft.truncate(index - 1) { // conflicts with the leader
+                            return Some(unstored(e, parcel.id));
+                        }
+                    }
+                    new.push(entry);
+                }
+                if !new.is_empty() {
+                    if let Err(e) = raft.append(new) {
+                        return Some(unstored(e, parcel.id));
+                    }
+                }
+
+                if leader_commit > raft.commit_index {
+                    raft.commit_index = min(leader_commit, index);
+                    raft.apply();
+                }
+                Some(ProtoParcel::append_res(raft.term, true, index))
+            }
+            Body::Forward { message } => {
+                debug!("Received forwarded publish with id {} from node {}", parcel.id, sender);
+                match self.append(message).await {
+                    Ok(()) => Some(ProtoParcel::ack(parcel.id)),
+                    Err(e) => Some(ProtoParcel::error(ProtoError::Unavailable, e.to_string(), parcel.id)),
+                }
+            }
+            _ => {
+                warn!("Unexpected {} for the raft engine", parcel.parcel_type());
+                Some(ProtoParcel::error(ProtoError::UnexpectedType, format!("{} isn't used by the raft engine", parcel.parcel_type()), parcel.id))
+            }
+        }
+    }
+
+    async fn run(&self) {
+        let neighbours = self.state.read().unwrap().get_neighbour_connections();
+        if let Err(e) = push_state(&neighbours, Mode::Wrk).await {
+            warn!("Not every neighbour knows this node is working, {}", e);
+        }
+
+        self.raft.lock().unwrap().last_heard = Instant::now();
+        loop {
+            let (role, term) = {
+                let raft = self.raft.lock().unwrap();
+                (raft.role, raft.term)
+            };
+            match role {
+                Role::Leader => self.lead(term).await,
+                Role::Follower | Role::Candidate => self.follow().await,
+            }
+        }
+    }
+
+    fn is_clear(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) == 0
+    }
+
+    async fn cleared(&self) {
+        loop {
+            let drained = self.drained.notified();
+            tokio::pin!(drained);
+            drained.as_mut().enable(); // register before checking so the last publish isn't missed
+
+            if self.is_clear() {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
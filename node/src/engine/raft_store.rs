@@ -0,0 +1,124 @@
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use log::warn;
+
+use crate::proto::LogEntry;
+
+// File in the data directory holding the current term and vote
+const STATE_FILE: &str = "raft_state";
+// File in the data directory holding the log, one length-prefixed entry after another
+const LOG_FILE: &str = "raft_log";
+
+#[derive(Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<Uuid>,
+}
+
+/// The term, vote and log of the raft engine, kept in the data directory.
+///
+/// Every write is synced to disk before it returns, so whatever a node answered a vote or an
+/// append with survives a crash. A restarted node can't vote twice in a term and doesn't lose
+/// entries a leader counted as stored.
+pub struct RaftStore {
+    dir: PathBuf,
+    log: File,
+    // where each entry of the log ends in the file
+    ends: Vec<u64>,
+}
+
+impl RaftStore {
+    /// Opens the store in `dir`, returning it along with the term, vote and log it holds. An entry
+    /// cut short by a crash is dropped.
+    pub fn open(dir: &Path) -> io::Result<(RaftStore, u64, Option<Uuid>, Vec<LogEntry>)> {
+        fs::create_dir_all(dir)?;
+
+        let (term, voted_for) = match fs::read(dir.join(STATE_FILE)) {
+            Ok(buf) => {
+                let state: HardState = serde_cbor::from_slice(&buf).map_err(|e| invalid(&dir.join(STATE_FILE), e))?;
+                (state.term, state.voted_for)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (0, None),
+            Err(e) => return Err(e),
+        };
+
+        let path = dir.join(LOG_FILE);
+        let buf = match fs::read(&path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        let mut entries = vec![];
+        let mut ends = vec![];
+        let mut at = 0;
+        while at + 8 <= buf.len() {
+            let len = u64::from_le_bytes(buf[at..at + 8].try_into().unwrap()) as usize;
+            if at + 8 + len > buf.len() {
+                break;
+            }
+            entries.push(serde_cbor::from_slice(&buf[at + 8..at + 8 + len]).map_err(|e| invalid(&path, e))?);
+            at += 8 + len;
+            ends.push(at as u64);
+        }
+
+        let log = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+        if at < buf.len() {
+            warn!("Dropping an incomplete entry at the end of {}", path.display());
+            log.set_len(at as u64)?;
+            log.sync_data()?;
+        }
+
+        Ok((RaftStore { dir: dir.to_path_buf(), log, ends }, term, voted_for, entries))
+    }
+
+    /// Replaces the term and vote.
+    pub fn save_vote(&self, term: u64, voted_for: Option<Uuid>) -> io::Result<()> {
+        let buf = serde_cbor::to_vec(&HardState { term, voted_for }).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        let tmp = self.dir.join(format!("{}.tmp", STATE_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(STATE_FILE))?;
+        File::open(&self.dir)?.sync_all()
+    }
+
+    /// Adds `entries` to the end of the log.
+    pub fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let mut buf = vec![];
+        let mut end = self.ends.last().copied().unwrap_or(0);
+        let mut ends = vec![];
+        for entry in entries {
+            let encoded = serde_cbor::to_vec(entry).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            buf.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+            buf.extend_from_slice(&encoded);
+            end += 8 + encoded.len() as u64;
+            ends.push(end);
+        }
+        self.log.write_all(&buf)?;
+        self.log.sync_data()?;
+        self.ends.extend(ends);
+        Ok(())
+    }
+
+    /// Drops every entry after the first `len`.
+    pub fn truncate(&mut self, len: usize) -> io::Result<()> {
+        if len >= self.ends.len() {
+            return Ok(());
+        }
+        let end = if len == 0 { 0 } else { self.ends[len - 1] };
+        self.log.set_len(end)?;
+        self.log.sync_data()?;
+        self.ends.truncate(len);
+        Ok(())
+    }
+}
+
+fn invalid(path: &Path, e: serde_cbor::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
@@ -0,0 +1,74 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::sync::Arc;
+
+use crate::proto::{ProtoError, Type};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// What went wrong talking to the cluster, by class of failure.
+///
+/// The same error may be reported to every request of a batch, so the underlying I/O and codec
+/// errors are shared rather than owned.
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// A connection couldn't be opened, or failed or was lost while in use.
+    Io(Arc<io::Error>),
+    /// A parcel couldn't be encoded or decoded.
+    Codec(Arc<serde_cbor::Error>),
+    /// The other end refused the request, or either end broke the protocol, e.g. a parcel over the
+    /// size limit or a response that doesn't answer the request.
+    Protocol { code: ProtoError, detail: String },
+    /// An operation took longer than it's allowed to, named here.
+    Timeout(&'static str),
+    /// The node and the cluster disagree on its membership, e.g. discovery was refused.
+    Membership(String),
+    /// The cluster couldn't settle the place of a message in the order.
+    Ordering(String),
+    /// The node can't reach a majority of the cluster, nothing can be ordered until it does.
+    NoQuorum,
+}
+
+impl Error {
+    /// A response of type `got` to a request expecting `expected`.
+    pub fn unexpected(expected: Type, got: Type) -> Error {
+        Error::Protocol { code: ProtoError::UnexpectedType, detail: format!("expected {}, got {}", expected, got) }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Codec(e) => write!(f, "Invalid parcel, {}", e),
+            Error::Protocol { code, detail } if detail.is_empty() => write!(f, "Protocol error, {}", code),
+            Error::Protocol { code, detail } => write!(f, "Protocol error, {}: {}", code, detail),
+            Error::Timeout(what) => write!(f, "{} timed out", what),
+            Error::Membership(detail) => write!(f, "Membership refused, {}", detail),
+            Error::Ordering(detail) => write!(f, "Ordering failed, {}", detail),
+            Error::NoQuorum => write!(f, "No quorum, this node can't reach a majority of the cluster"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e.as_ref()),
+            Error::Codec(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(Arc::new(e))
+    }
+}
+
+impl From<serde_cbor::Error> for Error {
+    fn from(e: serde_cbor::Error) -> Self {
+        Error::Codec(Arc::new(e))
+    }
+}
@@ -1,117 +1,216 @@
 use crate::state::{State, Mode, Node};
 use std::sync::{RwLock, Arc};
-use crossbeam_channel::{Sender, Receiver};
-use std::net::{TcpStream};
-use crate::proto::{ProtoParcel, Type};
-use rayon::prelude::*;
-use crate::net::{read_parcel, write_parcel};
-use clokwerk::{Scheduler, TimeUnits};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+use crate::proto::{ProtoParcel, Body};
+use futures::future::join_all;
+use crate::pool::Connection;
 use std::time::Duration;
 use crate::internal::TaskSignal;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::cmp::min;
+use crate::req::rejoin::rejoin;
+use rand::seq::SliceRandom;
 
-use log::{debug, error, info, warn};
+use log::{debug, error, info};
+use uuid::Uuid;
 
-pub fn heartbeat(state: Arc<RwLock<State>>, heart_rate: u32, timeout: u32, rx: Receiver<TaskSignal>) {
-    let mut scheduler = Scheduler::new();
-    // map node id to amount of timeouts
-    let mut timeouts: HashMap<u16, u8> = HashMap::new();
+// Members asked to ping a target that didn't answer directly
+const INDIRECT_PROBES: usize = 3;
+// Longest wait between probes of a timed out node
+const MAX_PROBE_BACKOFF: Duration = Duration::from_secs(120);
 
-    scheduler.every(heart_rate.seconds()).run(move || {
-        let state_ref = state.read().unwrap();
-        let new_keys: HashSet<u16> = state_ref.get_neighbour_keys();
+// When a timed out node is next probed, and how long to wait after that if it's still gone
+struct Probe {
+    next: Instant,
+    backoff: Duration,
+}
+
+// Failure detection in the style of SWIM. Every `period` one member is probed, so the
+// traffic grows with the cluster rather than its square. Members whose phi reaches `phi_threshold`
+// are suspected, and timed out if still suspected `timeout` periods later, see `Membership`.
+pub async fn heartbeat(state: Arc<RwLock<State>>, period: Duration, timeout: u32, phi_threshold: f64, mut rx: UnboundedReceiver<TaskSignal>) {
+    // map timed out node id to its next probe
+    let mut probes: HashMap<Uuid, Probe> = HashMap::new();
+
+    let mut beat = interval_at(Instant::now() + period, period);
+    beat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    info!("Started heartbeat thread!");
 
-        // Add new keys
-        for key in new_keys {
-            if !timeouts.contains_key(&key) {
-                info!("Adding {} to monitor", key);
-                timeouts.insert(key, 0);
+    loop {
+        tokio::select! {
+            _ = beat.tick() => {
+                monitor(&state, period, timeout, phi_threshold).await;
+                probe(&state, &mut probes, period).await;
+            }
+            Some(sig) = rx.recv() => {
+                match sig {
+                    TaskSignal::StopProcess => {
+                        info!("Stopping heartbeat thread!");
+                        return;
+                    }
+                    _ => {
+                        error!("Unknown signal sent to monitor thread")
+                    }
+                }
             }
         }
+    }
+}
 
-        if state_ref.mode == Mode::Wrk {
-            let (sender, receiver): (Sender<(u16, bool)>, Receiver<(u16, bool)>) = crossbeam_channel::unbounded(); // setup channel for results
+// One protocol period: times out members suspected for too long, suspects those whose phi got too
+// high, then probes the next member, directly and, failing that, through a few others.
+async fn monitor(state: &Arc<RwLock<State>>, period: Duration, timeout: u32, phi_threshold: f64) {
+    let (membership, size) = {
+        let state_ref = state.read().unwrap();
+        if state_ref.mode != Mode::Wrk {
+            return;
+        }
+        (state_ref.membership.clone(), state_ref.neighbours.len() + 1)
+    }; // drop lock
 
-            let neighbour_list: Vec<Node> = state_ref.get_active_neighbours();
-            drop(state_ref); // drop lock
+    let dead = membership.expired(period * timeout, size);
+    if !dead.is_empty() {
+        let mut state_ref = state.write().unwrap();
+        for id in dead {
+            error!("Node with id {} was suspected for more than {} heartbeats. ", id, timeout);
+            state_ref.neighbours.entry(id).and_modify(|x| { x.mode = Mode::TimedOut });
+        }
+    }
 
-            let req = ProtoParcel::ping();
+    let (target, helpers) = {
+        let mut state_ref = state.write().unwrap();
+        let active = state_ref.get_active_neighbour_keys();
+        for id in membership.detect(&active, phi_threshold, period, size) {
+            state_ref.neighbours.entry(id).and_modify(|x| { x.mode = Mode::Suspected });
+        }
 
-            // begin parallel scope
-            neighbour_list.into_par_iter().for_each_with(sender, |s, addr| {
-                ping(&addr, &req, s);
-            });
-            // end parallel scope
+        let target = match membership.next_target(&active) {
+            Some(id) => state_ref.neighbours.get(&id).map(|node| (node.clone(), state_ref.pool.get(node))),
+            None => None,
+        };
+        let helpers: Vec<Arc<Connection>> = state_ref.get_active_neighbours()
+            .choose_multiple(&mut rand::thread_rng(), INDIRECT_PROBES + 1)
+            .filter(|node| target.as_ref().is_none_or(|(target, _)| target.id != node.id))
+            .take(INDIRECT_PROBES)
+            .map(|node| state_ref.pool.get(node))
+            .collect();
+        (target, helpers)
+    }; // drop lock
 
-            for result in receiver.iter() {
-                let (id, node_response) = result;
+    let (node, conn) = match target {
+        Some(target) => target,
+        None => return,
+    };
 
-                if !node_response {
-                    let entry = timeouts.entry(id).or_insert(0);
-                    *entry += 1;
-                    warn!("Node with id {} timed out for {}", id, entry);
-                    if *entry > timeout as u8 {
-                        error!("Node with id {} timed out for more than {} heartbeats. ", id, timeout);
-                        let mut state_ref = state.write().unwrap();
-                        let node = state_ref.neighbours.entry(id);
-                        node.and_modify(|x| { x.mode = Mode::TimedOut });
-                    }
-                } else {
-                    timeouts.insert(id, 0);
-                }
+    let direct = period / 3;
+    if ping(state, &conn, direct).await {
+        heard_from(state, node.id);
+        return;
+    }
+
+    debug!("Node with id {} didn't answer, asking {} others", node.id, helpers.len());
+    let wait = period - direct;
+    // begin parallel scope
+    let results = join_all(helpers.iter().map(|helper| ping_through(state, helper, node.id, wait))).await;
+    // end parallel scope
+
+    if results.into_iter().any(|acked| acked) {
+        heard_from(state, node.id);
+    }
+}
+
+// Feeds the failure detector, taking back a suspected node that turned out to be alive
+pub fn heard_from(state: &Arc<RwLock<State>>, id: Uuid) {
+    let membership = state.read().unwrap().membership.clone();
+    if membership.heard(id) {
+        info!("Node with id {} is no longer suspected", id);
+        state.write().unwrap().neighbours.entry(id).and_modify(|x| {
+            if x.mode == Mode::Suspected {
+                x.mode = Mode::Wrk;
             }
-        } else {
+        });
+    }
+}
+
+// Pings timed out nodes that are due, with exponential backoff, and takes back the ones that answer
+async fn probe(state: &Arc<RwLock<State>>, probes: &mut HashMap<Uuid, Probe>, period: Duration) {
+    let now = Instant::now();
+    let due: Vec<(Node, Arc<Connection>)> = {
+        let state_ref = state.read().unwrap();
+        if state_ref.mode != Mode::Wrk {
             return;
         }
-    });
 
-    let thread_handle = scheduler.watch_thread(Duration::from_millis(100));
+        let timed_out: Vec<&Node> = state_ref.neighbours.values().filter(|node| node.mode == Mode::TimedOut).collect();
+        probes.retain(|id, _| timed_out.iter().any(|node| node.id == *id));
 
-    info!("Started heartbeat thread!");
+        timed_out.into_iter()
+            .filter(|node| probes.entry(node.id).or_insert(Probe { next: now + period, backoff: period }).next <= now)
+            .map(|node| (node.clone(), state_ref.pool.get(node)))
+            .collect()
+    }; // drop lock
 
-    for sig in rx.iter() {
-        match sig {
-            TaskSignal::StopProcess => {
-                thread_handle.stop();
-                info!("Stopping heartbeat thread!");
-                return;
-            }
-            _ => {
-                error!("Unknown signal sent to monitor thread")
-            }
+    for (node, conn) in due {
+        let back = ping(state, &conn, period).await && rejoin(&conn, state).await.is_ok();
+
+        if back {
+            info!("Node with id {} is back", node.id);
+            let membership = {
+                let mut state_ref = state.write().unwrap();
+                state_ref.neighbours.entry(node.id).and_modify(|x| { x.mode = Mode::Wrk });
+                state_ref.membership.clone()
+            };
+            membership.forget(node.id);
+            probes.remove(&node.id);
+        } else if let Some(probe) = probes.get_mut(&node.id) {
+            probe.backoff = min(probe.backoff * 2, MAX_PROBE_BACKOFF);
+            probe.next = Instant::now() + probe.backoff;
+            debug!("Node with id {} still gone, probing again in {:?}", node.id, probe.backoff);
         }
     }
 }
 
-fn ping(node: &Node, req_parcel: &ProtoParcel, tx: &mut Sender<(u16, bool)>) {
-    // info!("Sending Ping to {}", node.id);
+// Pings a member carrying membership updates, applying those in the answer. Whether it answered
+// within `wait`.
+pub async fn ping(state: &Arc<RwLock<State>>, conn: &Connection, wait: Duration) -> bool {
+    let membership = state.read().unwrap().membership.clone();
+    let req = ProtoParcel::ping(membership.piggyback());
 
-    let mut stream = match TcpStream::connect(node.external_addr) {
-        Ok(stream) => stream,
-        Err(err) => {
-            debug!("{}: {}", err, node.id);
-            tx.send((node.id, false)).unwrap();
-            return;
+    exchange(state, conn, req, wait).await
+}
+
+// Asks `helper` to ping `target` for us
+async fn ping_through(state: &Arc<RwLock<State>>, helper: &Connection, target: Uuid, wait: Duration) -> bool {
+    let membership = state.read().unwrap().membership.clone();
+    let req = ProtoParcel::ping_req(target, wait.as_millis() as u64, membership.piggyback());
+
+    exchange(state, helper, req, wait).await
+}
+
+async fn exchange(state: &Arc<RwLock<State>>, conn: &Connection, req_parcel: ProtoParcel, wait: Duration) -> bool {
+    let res_parcel = match tokio::time::timeout(wait, conn.request(req_parcel)).await {
+        Ok(Ok(parcel)) => parcel,
+        Ok(Err(e)) => {
+            debug!("{}: {}", e, conn.addr());
+            return false;
         }
-    };
-    write_parcel(&mut stream, &req_parcel);
-    let res_parcel = match read_parcel(&mut stream) {
-        Ok(parcel) => parcel,
-        Err(e) => {
-            error!("Invalid parcel! {}", e);
-            tx.send((node.id, false)).unwrap();
-            return;
+        Err(_) => {
+            debug!("Ping through {} timed out", conn.addr());
+            return false;
         }
     };
-    match res_parcel.parcel_type {
-        Type::Pong => {
-            tx.send((node.id, true)).unwrap();
+    match res_parcel.body {
+        Body::Pong { updates } => {
+            let membership = state.read().unwrap().membership.clone();
+            membership.receive(state, updates);
+            true
         }
-        Type::ProtoError => {}
-        _ => {
-            error!("Unexpected response type to Ping, {}", res_parcel.parcel_type);
-            tx.send((node.id, false)).unwrap();
-            return;
+        Body::Error { .. } => false,
+        body => {
+            error!("Unexpected response type to Ping, {}", body.parcel_type());
+            false
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,59 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::proto::MessageWrapper;
+
+/// How many delivered messages a node keeps unless configured otherwise.
+pub const DEFAULT_HISTORY: usize = 10000;
+
+/// The most recently delivered messages, in sequence order.
+///
+/// This is what a node that joins or rejoins the cluster catches up from. Only the last `capacity`
+/// messages are kept, a node that fell further behind than that can't recover the ones in between.
+pub struct History {
+    messages: Mutex<VecDeque<MessageWrapper>>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History { messages: Mutex::new(VecDeque::new()), capacity }
+    }
+
+    /// Keeps a delivered message, dropping the oldest one when full.
+    pub fn record(&self, message: MessageWrapper) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(message);
+    }
+
+    /// Drops every message after `sequence`.
+    pub fn truncate_after(&self, sequence: u64) {
+        let mut messages = self.messages.lock().unwrap();
+        let end = messages.partition_point(|message| message.sequence <= sequence);
+        messages.truncate(end);
+    }
+
+    /// Sequence number of the oldest message kept.
+    pub fn first(&self) -> Option<u64> {
+        self.messages.lock().unwrap().front().map(|message| message.sequence)
+    }
+
+    /// Up to `limit` of the messages that came after `sequence`, oldest first.
+    pub fn after(&self, sequence: u64, limit: usize) -> Vec<MessageWrapper> {
+        let messages = self.messages.lock().unwrap();
+        let start = messages.partition_point(|message| message.sequence <= sequence);
+        messages.range(start..).take(limit).cloned().collect()
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(DEFAULT_HISTORY)
+    }
+}
@@ -0,0 +1,50 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use uuid::Uuid;
+
+use log::info;
+
+// File in the data directory holding the node's id
+const ID_FILE: &str = "node_id";
+// File in the data directory holding the id of the cluster the node joined
+const CLUSTER_ID_FILE: &str = "cluster_id";
+
+/// The node's id, kept in `data_dir` so it survives restarts. A node starting in an empty
+/// directory picks a new random one.
+pub fn load_or_create(data_dir: &Path) -> io::Result<Uuid> {
+    if let Some(id) = read(data_dir, ID_FILE)? {
+        return Ok(id);
+    }
+    let id = Uuid::new_v4();
+    write(data_dir, ID_FILE, id)?;
+    info!("Created node id {} in {}", id, data_dir.display());
+    Ok(id)
+}
+
+/// Id of the cluster the node formed or joined before, if any.
+pub fn load_cluster_id(data_dir: &Path) -> io::Result<Option<Uuid>> {
+    read(data_dir, CLUSTER_ID_FILE)
+}
+
+/// Remembers the id of the cluster the node belongs to, so it can't be pointed at another one later.
+pub fn store_cluster_id(data_dir: &Path, id: Uuid) -> io::Result<()> {
+    write(data_dir, CLUSTER_ID_FILE, id)
+}
+
+fn read(data_dir: &Path, file: &str) -> io::Result<Option<Uuid>> {
+    let path = data_dir.join(file);
+    match fs::read_to_string(&path) {
+        Ok(contents) => Uuid::parse_str(contents.trim())
+            .map(Some)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write(data_dir: &Path, file: &str, id: Uuid) -> io::Result<()> {
+    fs::create_dir_all(data_dir)?;
+    fs::write(data_dir.join(file), id.to_string())
+}
@@ -1,7 +1,6 @@
+#[derive(Clone, Copy, PartialEq)]
 pub enum TaskSignal {
     StopProcess,
     StartProcess,
-    Success,
-    Fail,
     GracefulShutdown,
 }
\ No newline at end of file
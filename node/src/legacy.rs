@@ -0,0 +1,98 @@
+use std::str::FromStr;
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::proto::{Body, ParcelHeader, ProtoParcel, Type, Version};
+
+/// First protocol version in which a parcel's body alone tells its type.
+pub const SELF_DESCRIBING: Version = Version { major: 1, minor: 7 };
+
+/*
+    Parcels of protocol 1.6 and older named their type next to the body, and a few types shared an
+    empty body or went by another name. They're translated from and to the current format so nodes
+    still on an older version can be talked to during an upgrade.
+ */
+
+/// Whether parcels of `version` are written in the old format.
+pub fn is_legacy(version: &str) -> bool {
+    Version::from_str(version).is_ok_and(|version| version < SELF_DESCRIBING)
+}
+
+#[derive(Serialize)]
+struct Outgoing<'a> {
+    id: u64,
+    proto_version: &'a str,
+    sender_id: Uuid,
+    is_response: bool,
+    parcel_type: Type,
+    body: OutgoingBody<'a>,
+}
+
+#[derive(Deserialize)]
+struct Incoming<B> {
+    id: u64,
+    proto_version: String,
+    sender_id: Uuid,
+    is_response: bool,
+    parcel_type: Type,
+    body: B,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum OutgoingBody<'a> {
+    Same(&'a Body),
+    Old(Old<'a>),
+}
+
+// Bodies that only the old format has
+#[derive(Serialize)]
+enum Old<'a> {
+    Empty,
+    Publish { message: &'a [u8] },
+}
+
+#[derive(Deserialize)]
+enum OldOwned {
+    Empty,
+    Publish { message: Vec<u8> },
+}
+
+pub fn encode(parcel: &ProtoParcel) -> Result<Vec<u8>, serde_cbor::Error> {
+    let body = match &parcel.body {
+        Body::SeqReq | Body::SnapshotReq | Body::ExtAddrReq => OutgoingBody::Old(Old::Empty),
+        Body::Forward { message } => OutgoingBody::Old(Old::Publish { message }),
+        body => OutgoingBody::Same(body),
+    };
+    serde_cbor::to_vec(&Outgoing {
+        id: parcel.id,
+        proto_version: &parcel.proto_version,
+        sender_id: parcel.sender_id,
+        is_response: parcel.is_response,
+        parcel_type: parcel.parcel_type(),
+        body,
+    })
+}
+
+pub fn decode(buf: &[u8]) -> Result<ProtoParcel, serde_cbor::Error> {
+    // bodies the current format knows of are read as they are
+    let header: ParcelHeader = serde_cbor::from_slice(buf)?;
+    if header.parcel_type.is_some() {
+        let Incoming { id, proto_version, sender_id, is_response, body, .. } = serde_cbor::from_slice::<Incoming<Body>>(buf)?;
+        return Ok(ProtoParcel { id, proto_version, sender_id, is_response, body });
+    }
+
+    let Incoming { id, proto_version, sender_id, is_response, parcel_type, body } = serde_cbor::from_slice(buf)?;
+    let body = match (body, parcel_type) {
+        (OldOwned::Publish { message }, _) => Body::Forward { message },
+        (OldOwned::Empty, Type::SeqReq) => Body::SeqReq,
+        (OldOwned::Empty, Type::SnapshotReq) => Body::SnapshotReq,
+        // ExtAddrReq used to go out as a ResourceRelease
+        (OldOwned::Empty, Type::ExtAddrReq | Type::ResourceRelease) => Body::ExtAddrReq,
+        (OldOwned::Empty, parcel_type) => {
+            return Err(serde::de::Error::custom(format!("empty body for {}", parcel_type)));
+        }
+    };
+    Ok(ProtoParcel { id, proto_version, sender_id, is_response, body })
+}
@@ -13,3 +13,18 @@ pub mod internal;
 pub mod req;
 pub mod client;
 pub mod semaphore;
+pub mod pool;
+pub mod batch;
+pub mod outbox;
+pub mod engine;
+pub mod history;
+pub mod shutdown;
+pub mod membership;
+pub mod phi;
+pub mod identity;
+pub mod legacy;
+pub mod error;
+pub mod tls;
+pub mod auth;
+
+pub use error::{Error, Result};
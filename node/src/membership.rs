@@ -0,0 +1,234 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+
+use log::{error, info, warn};
+
+use crate::phi::Arrivals;
+use crate::proto::{Gossip, Health};
+use crate::state::{Mode, State};
+use uuid::Uuid;
+
+// Most updates carried by a single ping
+const MAX_PIGGYBACK: usize = 8;
+// Each update is sent this many times the log of the cluster size, enough to reach everyone
+const RETRANSMIT_MULT: usize = 3;
+
+struct Member {
+    incarnation: u64,
+    suspected: Option<Instant>,
+    arrivals: Arrivals,
+}
+
+impl Member {
+    fn new() -> Member {
+        Member { incarnation: 0, suspected: None, arrivals: Arrivals::new() }
+    }
+}
+
+struct Inner {
+    // ours, raised to refute suspicion
+    incarnation: u64,
+    members: HashMap<Uuid, Member>,
+    // updates still to piggyback, with how many more times to send each
+    updates: Vec<(Gossip, usize)>,
+    // probe targets left this round
+    order: Vec<Uuid>,
+}
+
+/// SWIM-style view of the cluster's health.
+///
+/// Members are probed one at a time in random order, directly or through others, and every answer
+/// or ping heard from a member feeds its phi-accrual detector. One whose phi goes over the threshold
+/// is suspected and its mode becomes `Suspected`. Suspicion and deaths spread on the back of pings,
+/// so a node learns about failures it never probed itself. A suspected member that doesn't refute it
+/// in time is dead and its mode becomes `TimedOut`.
+pub struct Membership {
+    inner: Mutex<Inner>,
+}
+
+impl Membership {
+    pub fn new() -> Membership {
+        Membership {
+            inner: Mutex::new(Inner { incarnation: 0, members: HashMap::new(), updates: vec![], order: vec![] }),
+        }
+    }
+
+    /// Picks the next member to probe out of `candidates`, going through them all in a random order
+    /// before starting over.
+    pub fn next_target(&self, candidates: &[Uuid]) -> Option<Uuid> {
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            match inner.order.pop() {
+                Some(id) if candidates.contains(&id) => return Some(id),
+                Some(_) => continue,
+                None if candidates.is_empty() => return None,
+                None => {
+                    inner.order = candidates.to_vec();
+                    inner.order.shuffle(&mut rand::thread_rng());
+                }
+            }
+        }
+    }
+
+    /// Updates to send along with a ping or its answer.
+    pub fn piggyback(&self) -> Vec<Gossip> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.updates.sort_by_key(|(_, left)| Reverse(*left)); // least spread first
+
+        let count = inner.updates.len().min(MAX_PIGGYBACK);
+        let picked = inner.updates.iter_mut().take(count).map(|(gossip, left)| {
+            *left -= 1;
+            gossip.clone()
+        }).collect();
+        inner.updates.retain(|(_, left)| *left > 0);
+        picked
+    }
+
+    /// The member was heard from, directly or through others. Whether that lifted our suspicion.
+    pub fn heard(&self, id: Uuid) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let member = inner.members.entry(id).or_insert_with(Member::new);
+        member.arrivals.heartbeat();
+        member.suspected.take().is_some()
+    }
+
+    /// Starts suspecting the `candidates` whose phi reached `threshold`, returning them. Members not
+    /// heard from yet are expected every `estimate`.
+    pub fn detect(&self, candidates: &[Uuid], threshold: f64, estimate: Duration, size: usize) -> Vec<Uuid> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut suspected = vec![];
+        for id in candidates {
+            let member = inner.members.entry(*id).or_insert_with(Member::new);
+            if member.suspected.is_some() {
+                continue;
+            }
+            let phi = member.arrivals.phi(estimate);
+            if phi >= threshold {
+                warn!("Suspecting node with id {}, phi {:.1}", id, phi);
+                member.suspected = Some(Instant::now());
+                let gossip = Gossip { id: *id, health: Health::Suspect, incarnation: member.incarnation };
+                spread(&mut inner, gossip, size);
+                suspected.push(*id);
+            }
+        }
+        suspected
+    }
+
+    /// Members suspected for longer than `timeout`, which are now dead.
+    pub fn expired(&self, timeout: Duration, size: usize) -> Vec<Uuid> {
+        let mut inner = self.inner.lock().unwrap();
+        let dead: Vec<(Uuid, u64)> = inner.members.iter()
+            .filter(|(_, member)| member.suspected.is_some_and(|since| since.elapsed() > timeout))
+            .map(|(id, member)| (*id, member.incarnation))
+            .collect();
+
+        for (id, incarnation) in &dead {
+            inner.members.remove(id);
+            spread(&mut inner, Gossip { id: *id, health: Health::Dead, incarnation: *incarnation }, size);
+        }
+        dead.into_iter().map(|(id, _)| id).collect()
+    }
+
+    /// Forgets a member that left or was taken back, its next incarnation starts afresh.
+    pub fn forget(&self, id: Uuid) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.members.remove(&id);
+        inner.updates.retain(|(gossip, _)| gossip.id != id);
+    }
+
+    /// Applies updates heard from another member, marking members suspected by others as `Suspected`,
+    /// those refuting it as working again and those found dead as `TimedOut`.
+    pub fn receive(&self, state: &Arc<RwLock<State>>, updates: Vec<Gossip>) {
+        if updates.is_empty() {
+            return;
+        }
+        let (self_id, working, size) = {
+            let state_ref = state.read().unwrap();
+            let working: HashSet<Uuid> = state_ref.get_active_neighbour_keys().into_iter().collect();
+            (state_ref.id, working, state_ref.neighbours.len() + 1)
+        };
+
+        let changes = {
+            let mut inner = self.inner.lock().unwrap();
+            let mut changes = vec![];
+            for gossip in updates {
+                if gossip.id == self_id {
+                    refute(&mut inner, self_id, gossip, size);
+                } else if working.contains(&gossip.id) && apply(&mut inner, &gossip) {
+                    changes.push((gossip.id, gossip.health));
+                    spread(&mut inner, gossip, size);
+                }
+            }
+            changes
+        };
+
+        if !changes.is_empty() {
+            let mut state_ref = state.write().unwrap();
+            for (id, health) in changes {
+                let node = match state_ref.neighbours.get_mut(&id) {
+                    Some(node) => node,
+                    None => continue,
+                };
+                match health {
+                    Health::Alive => node.mode = Mode::Wrk,
+                    Health::Suspect => node.mode = Mode::Suspected,
+                    Health::Dead => {
+                        error!("Node with id {} was found dead by the cluster", id);
+                        node.mode = Mode::TimedOut;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for Membership {
+    fn default() -> Self {
+        Membership::new()
+    }
+}
+
+// Whether `gossip` is news about a member. Newer incarnations override older ones and, for the same
+// incarnation, suspicion overrides being alive and death overrides both.
+fn apply(inner: &mut Inner, gossip: &Gossip) -> bool {
+    let member = inner.members.entry(gossip.id).or_insert_with(Member::new);
+    match gossip.health {
+        Health::Alive if gossip.incarnation > member.incarnation => {
+            member.incarnation = gossip.incarnation;
+            member.suspected = None;
+            true
+        }
+        Health::Suspect if gossip.incarnation > member.incarnation ||
+            (gossip.incarnation == member.incarnation && member.suspected.is_none()) => {
+            member.incarnation = gossip.incarnation;
+            member.suspected = Some(Instant::now());
+            true
+        }
+        Health::Dead if gossip.incarnation >= member.incarnation => {
+            inner.members.remove(&gossip.id);
+            true
+        }
+        _ => false,
+    }
+}
+
+// Someone thinks this node is failing. It answers with a higher incarnation, which overrides that.
+fn refute(inner: &mut Inner, self_id: Uuid, gossip: Gossip, size: usize) {
+    if gossip.health != Health::Alive && gossip.incarnation >= inner.incarnation {
+        inner.incarnation = gossip.incarnation + 1;
+        info!("Refuting {:?} with incarnation {}", gossip.health, inner.incarnation);
+        let incarnation = inner.incarnation;
+        spread(inner, Gossip { id: self_id, health: Health::Alive, incarnation }, size);
+    }
+}
+
+// Queues `gossip` for piggybacking, replacing anything older about the same member
+fn spread(inner: &mut Inner, gossip: Gossip, size: usize) {
+    let sends = RETRANSMIT_MULT * (usize::BITS - size.leading_zeros()).max(1) as usize;
+    inner.updates.retain(|(queued, _)| queued.id != gossip.id);
+    inner.updates.push((gossip, sends));
+}
@@ -1,196 +1,600 @@
-use std::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpStream};
+use std::net::SocketAddr;
+use std::io;
+use std::str::FromStr;
 
-use crate::state::{State, Node};
+use crate::state::{State, Node, Mode};
 
 
-use std::sync::{Arc, RwLock, Mutex};
-use std::io::{Read, Write};
-use crate::proto::{ProtoParcel, Type, Body, ResourceRequest, ResourceRelease};
-use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
-use crate::internal::TaskSignal;
+use std::sync::{Arc, Mutex, RwLock};
+use lazy_static::lazy_static;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::proto::{get_max_parcel_size, ProtoParcel, ParcelHeader, Type, Body, Handshake, ProtoError, ProtoErrorKind, Version, PROTO_VERSION, MIN_PROTO_VERSION};
+use crate::error::{Error, Result};
+use crate::tls::{secure_incoming, secure_outgoing, Listener, Stream};
+use crate::auth::{seal_parcel, open_parcel};
 use crate::req::add_node::add_node;
+use crate::req::replicate::catch_up;
+use crate::heartbeat::{ping, heard_from};
+use std::time::Duration;
 
-use log::{error, info, debug};
-use std::collections::{BinaryHeap};
-use std::error::Error;
-use crossbeam_channel::{Sender};
-use chrono::{DateTime, Utc};
-use crate::semaphore::OrdSemaphore;
+use log::{error, info, debug, warn};
+use tokio::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use tokio::sync::Semaphore;
+use crate::engine::OrderingEngine;
+use uuid::Uuid;
 
+/// How long network operations may take before they're given up on.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Opening a connection.
+    pub connect: Duration,
+    /// Receiving a parcel once it's expected, a reply to a one-off request or the rest of a parcel
+    /// that started coming in.
+    pub read: Duration,
+    /// Writing a parcel.
+    pub write: Duration,
+    /// Waiting for the answer to a request sent over a neighbour's connection.
+    pub request: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(3),
+            read: Duration::from_secs(10),
+            write: Duration::from_secs(10),
+            request: Duration::from_secs(10),
+        }
+    }
+}
+
+lazy_static! {
+    static ref TIMEOUTS: Mutex<Timeouts> = Mutex::new(Timeouts::default());
+}
+
+pub fn get_timeouts() -> Timeouts {
+    *TIMEOUTS.lock().unwrap()
+}
+
+pub fn set_timeouts(timeouts: Timeouts) {
+    *TIMEOUTS.lock().unwrap() = timeouts;
+}
+
+/// Connects to `addr` over TLS if it's enabled, failing with `Error::Timeout` if it takes too long.
+pub async fn connect(addr: &SocketAddr) -> Result<Stream> {
+    let connecting = async {
+        let stream = TcpStream::connect(addr).await?;
+        secure_outgoing(stream, addr).await
+    };
+    match tokio::time::timeout(get_timeouts().connect, connecting).await {
+        Ok(stream) => stream,
+        Err(_) => Err(Error::Timeout("Connecting")),
+    }
+}
+
+/// Takes a connection accepted by `listener`, over TLS if it's enabled, failing with
+/// `Error::Timeout` if the handshake takes too long.
+pub async fn accept(stream: TcpStream, listener: Listener) -> Result<Stream> {
+    match tokio::time::timeout(get_timeouts().connect, secure_incoming(stream, listener)).await {
+        Ok(stream) => stream,
+        Err(_) => Err(Error::Timeout("Accepting a connection")),
+    }
+}
 
-pub fn read_parcel(stream: &mut TcpStream) -> Result<ProtoParcel, Box<dyn Error>> {
-    let size: u64 = stream.read_u64::<LittleEndian>()?;
+/// Reads the answer to a request sent on its own stream, failing with `Error::Timeout` if it takes
+/// too long.
+pub async fn read_reply(stream: &mut Stream) -> Result<ProtoParcel> {
+    match tokio::time::timeout(get_timeouts().read, read_parcel(stream)).await {
+        Ok(parcel) => parcel,
+        Err(_) => Err(Error::Timeout("Waiting for a reply")),
+    }
+}
 
-    // debug!("Expecting {} bytes", size);
-    let mut buf = vec![0u8; size as usize];
-    stream.read_exact(&mut buf)?;
+pub async fn read_parcel<R: AsyncRead + Unpin>(stream: &mut R) -> Result<ProtoParcel> {
+    let buf = open_parcel(read_frame(stream).await?)?;
 
-    let proto_parcel: ProtoParcel = serde_cbor::from_slice(buf.as_slice())?;
+    let proto_parcel = ProtoParcel::decode(buf.as_slice())?;
     Ok(proto_parcel)
 }
 
-pub fn write_parcel(stream: &mut TcpStream, parcel: &ProtoParcel) {
-    let parcel = serde_cbor::to_vec(&parcel).unwrap();
-    let buf = parcel.as_slice();
-    let count: u64 = buf.len() as u64;
+// Reads the bytes of the next parcel without decoding them. A parcel larger than the limit is refused
+// before anything is allocated for it, the connection can't be used after that. The next parcel may
+// be waited for indefinitely, but once it starts coming in the rest of it has to arrive in time.
+async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Vec<u8>> {
+    let header: u64 = stream.read_u64_le().await?;
+    match tokio::time::timeout(get_timeouts().read, read_chunks(stream, header)).await {
+        Ok(buf) => buf,
+        Err(_) => Err(Error::Timeout("Reading a parcel")),
+    }
+}
+
+// Reads the chunks of a parcel, the first one starting with `header`
+async fn read_chunks<R: AsyncRead + Unpin>(stream: &mut R, mut header: u64) -> Result<Vec<u8>> {
+    let limit = get_max_parcel_size();
+    let mut buf = vec![];
+    loop {
+        let size = header & !MORE_CHUNKS;
+
+        // debug!("Expecting {} bytes", size);
+        if buf.len() as u64 + size > limit as u64 {
+            return Err(too_large(buf.len() as u64 + size, limit));
+        }
+        let start = buf.len();
+        buf.resize(start + size as usize, 0);
+        stream.read_exact(&mut buf[start..]).await?;
+
+        if header & MORE_CHUNKS == 0 {
+            return Ok(buf);
+        }
+        header = stream.read_u64_le().await?;
+    }
+}
 
-    // debug!("Writing {} bytes", count);
-    stream.write_u64::<LittleEndian>(count).unwrap();
-    stream.write_all(buf).unwrap();
+/// Writes an encoded parcel, in chunks if the receiver reads them, failing with `Error::Timeout` if
+/// it takes too long.
+pub async fn write_frame<W: AsyncWrite + Unpin>(stream: &mut W, buf: &[u8], chunked: bool) -> Result<()> {
+    match tokio::time::timeout(get_timeouts().write, write_chunks(stream, buf, chunked)).await {
+        Ok(written) => Ok(written?),
+        Err(_) => Err(Error::Timeout("Writing a parcel")),
+    }
 }
 
-pub fn is_acked(response: ProtoParcel, ack_id: u64) -> TaskSignal {
-    match response.parcel_type {
-        Type::Ack => {
-            if let Body::Ack { message_id } = response.body {
-                if message_id == ack_id {
-                    debug!("Acked {}", message_id);
-                    TaskSignal::Success
-                } else {
-                    TaskSignal::Fail
-                }
+async fn write_chunks<W: AsyncWrite + Unpin>(stream: &mut W, buf: &[u8], chunked: bool) -> io::Result<()> {
+    let chunk_size = if chunked { CHUNK_SIZE } else { buf.len().max(1) };
+    let mut chunks = buf.chunks(chunk_size).peekable();
+    while let Some(chunk) = chunks.next() {
+        let more = if chunks.peek().is_some() { MORE_CHUNKS } else { 0 };
+        // debug!("Writing {} bytes", chunk.len());
+        stream.write_u64_le(chunk.len() as u64 | more).await?;
+        stream.write_all(chunk).await?;
+    }
+    stream.flush().await
+}
+
+/// The parcel in its wire format, sealed if the cluster shares a secret, as long as it's within the
+/// size limit.
+pub fn encode_parcel(parcel: &ProtoParcel) -> Result<Vec<u8>> {
+    let buf = seal_parcel(parcel.encode()?);
+    let limit = get_max_parcel_size();
+    if buf.len() > limit {
+        return Err(too_large(buf.len() as u64, limit));
+    }
+    Ok(buf)
+}
+
+/// Whether parcels of `version` may be split into chunks.
+pub fn is_chunked(version: &str) -> bool {
+    Version::from_str(version).is_ok_and(|version| version >= CHUNKED)
+}
+
+fn too_large(size: u64, limit: usize) -> Error {
+    Error::Protocol { code: ProtoError::TooLarge, detail: format!("parcel of {} bytes exceeds the limit of {}", size, limit) }
+}
+
+pub async fn write_parcel<W: AsyncWrite + Unpin>(stream: &mut W, parcel: &ProtoParcel) -> Result<()> {
+    let buf = encode_parcel(parcel)?;
+    write_frame(stream, &buf, is_chunked(&parcel.proto_version)).await
+}
+
+/// Whether `response` acknowledges request `ack_id`, or the error it was refused with.
+pub fn is_acked(response: ProtoParcel, ack_id: u64) -> Result<()> {
+    match response.body {
+        Body::Ack { message_id } => {
+            if message_id == ack_id {
+                debug!("Acked {}", message_id);
+                Ok(())
             } else {
-                error!("Body-header type mismatch!");
-                TaskSignal::Fail
+                Err(Error::Protocol { code: ProtoError::UnexpectedType, detail: format!("acknowledged {} instead of {}", message_id, ack_id) })
             }
         }
-        Type::ProtoError => {
-            TaskSignal::Fail
+        Body::Error { code, detail, in_reply_to } => {
+            error!("Request {} refused: {}, {}", in_reply_to, code, detail);
+            Err(Error::Protocol { code, detail })
         }
-        _ => {
-            error!("Expected acknowledge, got {}", response.parcel_type);
-            TaskSignal::Fail
+        body => {
+            error!("Expected acknowledge, got {}", body.parcel_type());
+            Err(Error::unexpected(Type::Ack, body.parcel_type()))
         }
     }
 }
 
-pub fn listener_thread(socket: TcpListener, state: Arc<RwLock<State>>, resource_queue: Arc<Mutex<BinaryHeap<ResourceRequest>>>,
-                       semaphore: Arc<OrdSemaphore<DateTime<Utc>>>, wrk: Sender<ResourceRelease>) {
+// Most requests handled at once for a single connection, any more are refused as overloaded
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// First protocol version that reads parcels split into chunks.
+pub const CHUNKED: Version = Version { major: 1, minor: 8 };
+// Largest piece of a parcel written at once
+const CHUNK_SIZE: usize = 64 * 1024;
+// Set in a chunk's length when more of the parcel follows
+const MORE_CHUNKS: u64 = 1 << 63;
+/// How long a listener waits before accepting again after failing to, running out of file
+/// descriptors won't be over right away.
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Handles shared by every connection served by the listener
+#[derive(Clone)]
+pub(crate) struct Shared {
+    pub(crate) state: Arc<RwLock<State>>,
+    pub(crate) engine: Arc<dyn OrderingEngine>,
+}
+
+pub async fn listener_thread(socket: TcpListener, state: Arc<RwLock<State>>, engine: Arc<dyn OrderingEngine>) {
     info!("Started Listener thread!");
 
-    for stream in socket.incoming() {
-        let mut stream = stream.unwrap();
+    let shared = Shared { state, engine };
+
+    loop {
+        let (stream, peer_addr) = match socket.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Couldn't accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            // with TLS, only members of the cluster get past the handshake
+            match accept(stream, Listener::Cluster).await {
+                Ok(stream) => serve(stream, peer_addr, shared).await,
+                Err(e) => error!("Refusing connection from {}: {}", peer_addr, e),
+            }
+        });
+    }
+}
 
-        let state_ref = Arc::clone(&state);
-        let pledge_queue = Arc::clone(&resource_queue);
-        let wrk = wrk.clone();
-        let semaphore = semaphore.clone();
+// Serves parcels from a single connection until it is closed. Parcels are handled concurrently
+// and each response carries the id of the parcel it answers. Over mutual TLS, parcels are only taken
+// from the node the peer's certificate was issued to.
+pub(crate) async fn serve(stream: Stream, peer_addr: SocketAddr, shared: Shared) {
+    if let Err(err) = stream.set_nodelay(true) {
+        error!("{}: {}", err, peer_addr);
+    }
+    let peer_id = match stream.peer_id() {
+        Ok(peer_id) => peer_id,
+        Err(e) => {
+            error!("Refusing connection from {}: {}", peer_addr, e);
+            return;
+        }
+    };
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (responses, mut outgoing): (UnboundedSender<ProtoParcel>, UnboundedReceiver<ProtoParcel>) = mpsc::unbounded_channel();
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
 
-        rayon::spawn(move || {
-            let parcel = match read_parcel(&mut stream) {
-                Ok(parcel) => parcel,
+    let mut writing = tokio::spawn(async move {
+        while let Some(parcel) = outgoing.recv().await {
+            let buf = match encode_parcel(&parcel) {
+                Ok(buf) => buf,
                 Err(e) => {
-                    error!("Invalid parcel! {}", e);
-                    return;
+                    // the requester is still owed an answer
+                    error!("Can't answer {} from {}: {}", parcel.id, peer_addr, e);
+                    let (code, detail) = match e {
+                        Error::Protocol { code, detail } => (code, detail),
+                        e => (ProtoError::MalformedBody, e.to_string()),
+                    };
+                    let mut error = ProtoParcel::error(code, detail, parcel.id);
+                    error.id = parcel.id;
+                    error.proto_version = parcel.proto_version.clone();
+                    match error.encode() {
+                        Ok(buf) => seal_parcel(buf),
+                        Err(e) => {
+                            error!("Can't answer {} from {}, dropping the connection: {}", parcel.id, peer_addr, e);
+                            return;
+                        }
+                    }
                 }
             };
+            if let Err(e) = write_frame(&mut writer, &buf, is_chunked(&parcel.proto_version)).await {
+                error!("Can't answer {}: {}", peer_addr, e);
+                return;
+            }
+        }
+    });
 
-            match parcel.parcel_type {
-                Type::DscReq => {
-                    if let Body::DscReq { identity } = parcel.body {
-                        info!("Received DscReq with id {} from node {}", parcel.id, parcel.sender_id);
-
-                        let mut neighbours = vec![];
-                        let mut state_ref = state_ref.write().unwrap(); // acquire write lock
-                        let state_neighbours: Vec<Node> = state_ref.neighbours.values().cloned().collect();
-                        let self_node = state_ref.get_node_information();
-
-                        // Push found node to neighbours
-                        let mut update: Vec<Node> = Vec::new();
-                        update.push(identity.clone());
-                        info!("Pushing new node to neighbours!");
-                        add_node(&state_ref.get_neighbour_addrs(), update);
-
-                        info!("Adding {} to state", identity.name);
-                        state_ref.add_neighbour(identity); // add node to state after neighbours are cloned
-                        drop(state_ref); // drop write lock before tcp writes
-
-                        neighbours.extend_from_slice(state_neighbours.as_slice());
-                        let parcel = ProtoParcel::dsc_res(neighbours, self_node);
-
-                        write_parcel(&mut stream, &parcel);
-                    } else {
-                        error!("Body-header type mismatch!");
-                        return;
-                    }
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame(&mut reader) => frame,
+            _ = &mut writing => return, // nothing can be answered anymore
+        };
+        let buf = match frame {
+            Ok(buf) => buf,
+            Err(e) => {
+                match e {
+                    Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => debug!("Connection from {} closed", peer_addr),
+                    e => error!("Invalid parcel! {}", e),
                 }
+                return;
+            }
+        };
+        // nothing in a parcel that isn't sealed can be trusted, not even its id to answer it with
+        let buf = match open_parcel(buf) {
+            Ok(buf) => buf,
+            Err(e) => {
+                error!("Refusing connection from {}: {}", peer_addr, e);
+                return;
+            }
+        };
 
-                Type::SeqReq => {
-                    info!("Received SeqReq with id {} from node {}", parcel.id, parcel.sender_id);
-                    let state = state_ref.write().unwrap(); // acquire write lock
-                    let seq = state.sequence;
-                    let parcel = ProtoParcel::seq_res(seq);
-                    drop(state);
-                    write_parcel(&mut stream, &parcel);
-                }
-                Type::Ping => {
-                    // debug!("Received Ping with id {} from node {}", parcel.id, parcel.sender_id);
-                    let state = state_ref.write().unwrap(); // acquire write lock
-                    let parcel = ProtoParcel::pong();
-                    drop(state);
-                    write_parcel(&mut stream, &parcel);
+        // discovery negotiates the version, everything else has to be in one this node speaks
+        let header: ParcelHeader = match serde_cbor::from_slice(buf.as_slice()) {
+            Ok(header) => header,
+            Err(e) => {
+                error!("Invalid parcel from {}! {}", peer_addr, e);
+                return;
+            }
+        };
+        if header.parcel_type != Some(Type::DscReq) && !Version::supported(&header.proto_version) {
+            error!("Refusing parcel {} from {} speaking protocol {}", header.id, peer_addr, header.proto_version);
+            let mut refusal = ProtoParcel::refusal(ProtoErrorKind::VersionMismatch { min: MIN_PROTO_VERSION, max: PROTO_VERSION }, header.id);
+            refusal.id = header.id;
+            drop(responses.send(refusal));
+            continue;
+        }
+        let parcel = match ProtoParcel::decode(buf.as_slice()) {
+            Ok(parcel) => parcel,
+            Err(e) => {
+                // the frame itself was read whole, so the connection is still usable
+                error!("Malformed parcel {} from {}! {}", header.id, peer_addr, e);
+                let mut error = ProtoParcel::error(ProtoError::MalformedBody, e.to_string(), header.id);
+                error.id = header.id;
+                drop(responses.send(error));
+                continue;
+            }
+        };
+        if let Err(detail) = sent_by(&parcel, peer_id) {
+            error!("Refusing {} from {}, {}", parcel.parcel_type(), peer_addr, detail);
+            let mut error = ProtoParcel::error(ProtoError::Unauthenticated, detail, parcel.id);
+            error.id = parcel.id;
+            drop(responses.send(error));
+            continue;
+        }
+        let permit = match in_flight.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                error!("Refusing {} from {}, too many requests in flight", parcel.parcel_type(), peer_addr);
+                let mut error = ProtoParcel::error(ProtoError::Overloaded, format!("more than {} requests in flight", MAX_IN_FLIGHT), parcel.id);
+                error.id = parcel.id;
+                drop(responses.send(error));
+                continue;
+            }
+        };
+
+        let shared = shared.clone();
+        let responses = responses.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let (request_id, version) = (parcel.id, parcel.proto_version.clone());
+            if let Some(mut response) = handle(parcel, peer_addr, shared).await {
+                // answer in the version asked in
+                response.id = request_id;
+                if Version::supported(&version) {
+                    response.proto_version = version;
                 }
-                Type::ProtoError => {
-                    error!("Proto Error")
+                drop(responses.send(response));
+            }
+        });
+    }
+}
+
+// Whether `parcel` only speaks for its sender, and the sender is the node `peer_id` the connection
+// was authenticated as, if it was
+fn sent_by(parcel: &ProtoParcel, peer_id: Option<Uuid>) -> std::result::Result<(), String> {
+    if let Some(id) = peer_id {
+        if id != parcel.sender_id {
+            return Err(format!("sent as node {} by node {}", parcel.sender_id, id));
+        }
+    }
+    let claimed: Vec<Uuid> = match &parcel.body {
+        Body::DscReq { identity, .. } | Body::RejoinReq { identity, .. } => vec![identity.id],
+        Body::ResourceRequest { resource_request } => vec![resource_request.owner],
+        Body::ResourceRelease { resource_release } => vec![resource_release.owner],
+        Body::ResourceRequestBatch { resource_requests } => resource_requests.iter().map(|req| req.owner).collect(),
+        Body::ResourceReleaseBatch { resource_releases } => resource_releases.iter().map(|rel| rel.owner).collect(),
+        _ => vec![],
+    };
+    match claimed.into_iter().find(|id| *id != parcel.sender_id) {
+        Some(id) => Err(format!("node {} speaks for node {}", parcel.sender_id, id)),
+        None => Ok(()),
+    }
+}
+
+async fn handle(parcel: ProtoParcel, peer_addr: SocketAddr, shared: Shared) -> Option<ProtoParcel> {
+    let Shared { state: state_ref, engine } = shared;
+
+    match parcel.body {
+        Body::DscReq { identity, cluster, handshake } => {
+            info!("Received DscReq with id {} from node {}", parcel.id, parcel.sender_id);
+
+            // the whole cluster has to agree on the protocol, engine and release mode included
+            if let Err(kind) = Handshake::local().negotiate(&handshake) {
+                error!("Refusing {}: {}", identity.name, kind);
+                return Some(ProtoParcel::refusal(kind, parcel.id));
+            }
+
+            let mut neighbours = vec![];
+            let (state_neighbours, self_node, own_cluster, neighbour_connections, restarted) = {
+                let mut state_ref = state_ref.write().unwrap(); // acquire write lock
+
+                if !state_ref.cluster.accepts(&cluster) {
+                    error!("Refusing {} from cluster {}, this is {}", identity.name, cluster, state_ref.cluster);
+                    return Some(ProtoParcel::refusal(ProtoErrorKind::ClusterMismatch, parcel.id));
                 }
-                Type::StateChange => {
-                    if let Body::StateChange { mode } = parcel.body {
-                        info!("Received StateChange with id {} from node {}", parcel.id, parcel.sender_id);
-                        let mut state = state_ref.write().unwrap();
-                        state.neighbours.entry(parcel.sender_id).and_modify(|node| {
-                            node.mode = mode
-                        });
-                        drop(state);
-                        let ack = ProtoParcel::ack(parcel.id);
-                        write_parcel(&mut stream, &ack);
-                    }
+
+                // ids are only known to be the same node if they come from the same address
+                let taken = if identity.id == state_ref.id {
+                    state_ref.external_addr.is_none_or(|addr| addr != identity.external_addr)
+                } else {
+                    state_ref.neighbours.get(&identity.id).is_some_and(|node| node.external_addr != identity.external_addr)
+                };
+                if taken {
+                    error!("Refusing {} at {}, its id {} is already taken", identity.name, identity.external_addr, identity.id);
+                    return Some(ProtoParcel::refusal(ProtoErrorKind::IdTaken, parcel.id));
                 }
-                Type::AddNode => {
-                    if let Body::AddNode { nodes } = parcel.body {
-                        info!("Received AddNode with id {} from node {}", parcel.id, parcel.sender_id);
-                        let mut state = state_ref.write().unwrap();
-                        for node in nodes {
-                            state.add_neighbour(node);
-                        }
-                        drop(state);
-                        let ack = ProtoParcel::ack(parcel.id);
-                        write_parcel(&mut stream, &ack);
-                    }
+
+                let state_neighbours: Vec<Node> = state_ref.neighbours.values().filter(|node| node.id != identity.id).cloned().collect();
+                let self_node = state_ref.get_node_information();
+                let neighbour_connections = state_ref.get_neighbour_connections();
+
+                // a node we already know of has restarted, it rejoins rather than joins
+                let restarted = state_ref.neighbours.contains_key(&identity.id);
+                if restarted {
+                    info!("{} restarted, rejoining", identity.name);
+                    state_ref.pool.remove(identity.id);
+                } else {
+                    info!("Adding {} to state", identity.name);
                 }
-                Type::ResourceRequest => {
-                    if let Body::ResourceRequest { resource_request } = parcel.body {
-                        info!("Processing Resource Request with id {} from node {}", parcel.id, parcel.sender_id);
+                state_ref.add_neighbour(identity.clone()); // add node to state after neighbours are cloned
+                (state_neighbours, self_node, state_ref.cluster.clone(), neighbour_connections, restarted)
+            }; // drop write lock before tcp writes
 
-                        semaphore.wait_until(&resource_request.timestamp);
+            if restarted {
+                // its requests from before the restart will never be released
+                engine.forget(identity.id);
+                state_ref.read().unwrap().membership.forget(identity.id);
+            }
 
-                        let mut pledge_queue = pledge_queue.lock().unwrap();
+            // Push found node to neighbours
+            let update: Vec<Node> = vec![identity];
+            info!("Pushing new node to neighbours!");
+            if let Err(e) = add_node(&neighbour_connections, update).await {
+                warn!("Not every neighbour knows of the new node, {}", e);
+            }
 
-                        pledge_queue.push(resource_request);
-                        drop(pledge_queue);
-                        let ack = ProtoParcel::ack(parcel.id);
-                        write_parcel(&mut stream, &ack);
-                    }
-                }
-                Type::ResourceRelease => {
-                    if let Body::ResourceRelease { resource_release } = parcel.body {
-                        info!("Processing Resource Release with hash {} from node {}", resource_release.shorthand, parcel.sender_id);
+            neighbours.extend_from_slice(state_neighbours.as_slice());
+            Some(ProtoParcel::dsc_res(neighbours, self_node, own_cluster))
+        }
 
-                        wrk.send(resource_release).unwrap();
-                        let parcel = ProtoParcel::ack(parcel.id);
-                        write_parcel(&mut stream, &parcel);
-                    }
+        Body::SeqReq => {
+            info!("Received SeqReq with id {} from node {}", parcel.id, parcel.sender_id);
+            let seq = state_ref.read().unwrap().sequence.current();
+            Some(ProtoParcel::seq_res(seq))
+        }
+        Body::CatchUpReq { after, limit } => {
+            info!("Received CatchUpReq after {} with id {} from node {}", after, parcel.id, parcel.sender_id);
+            let history = state_ref.read().unwrap().history.clone();
+            Some(ProtoParcel::catch_up_res(history.after(after, limit as usize)))
+        }
+        Body::SnapshotReq => {
+            info!("Received SnapshotReq with id {} from node {}", parcel.id, parcel.sender_id);
+            let snapshot = state_ref.read().unwrap().snapshot();
+            Some(ProtoParcel::snapshot_res(snapshot))
+        }
+        Body::RejoinReq { identity, sequence } => {
+            info!("Received RejoinReq with id {} from node {}", parcel.id, parcel.sender_id);
+            let (mode, own_sequence, history, conn) = {
+                let state_ref = state_ref.read().unwrap();
+                (state_ref.mode.clone(), state_ref.sequence.clone(), state_ref.history.clone(), state_ref.pool.get(&identity))
+            };
+            if mode != Mode::Wrk {
+                return Some(ProtoParcel::error(ProtoError::Unavailable, format!("not working, {}", mode), parcel.id));
+            }
+
+            // the node may have kept going without us
+            if let Err(e) = catch_up(&[conn], &own_sequence, &history, sequence).await {
+                warn!("{}", e);
+            }
+
+            info!("{} rejoined", identity.name);
+            let mut node = identity;
+            node.mode = Mode::Wrk;
+            let membership = {
+                let mut state_ref = state_ref.write().unwrap();
+                state_ref.add_neighbour(node.clone());
+                state_ref.membership.clone()
+            };
+            membership.forget(node.id);
+            Some(ProtoParcel::rejoin_res(own_sequence.current()))
+        }
+        Body::Ping { updates } => {
+            // debug!("Received Ping with id {} from node {}", parcel.id, parcel.sender_id);
+            heard_from(&state_ref, parcel.sender_id);
+            let membership = state_ref.read().unwrap().membership.clone();
+            membership.receive(&state_ref, updates);
+            Some(ProtoParcel::pong(membership.piggyback()))
+        }
+        Body::PingReq { target, timeout_ms, updates } => {
+            debug!("Received PingReq for node {} from node {}", target, parcel.sender_id);
+            heard_from(&state_ref, parcel.sender_id);
+            let (membership, conn) = {
+                let state_ref = state_ref.read().unwrap();
+                (state_ref.membership.clone(), state_ref.neighbours.get(&target).map(|node| state_ref.pool.get(node)))
+            };
+            membership.receive(&state_ref, updates);
+
+            let acked = match conn {
+                Some(conn) => ping(&state_ref, &conn, Duration::from_millis(timeout_ms)).await,
+                None => false,
+            };
+            if acked {
+                Some(ProtoParcel::pong(membership.piggyback()))
+            } else {
+                Some(ProtoParcel::error(ProtoError::Unavailable, format!("no answer from {}", target), parcel.id))
+            }
+        }
+        Body::Error { .. } => {
+            // errors answer requests, they aren't requests themselves
+            error!("Unexpected error from node {}", parcel.sender_id);
+            None
+        }
+        Body::StateChange { mode } => {
+            info!("Received StateChange with id {} from node {}", parcel.id, parcel.sender_id);
+            if mode == Mode::Shutdown {
+                // a node shutting down leaves the cluster, so it no longer counts towards a majority
+                info!("Node {} left the cluster", parcel.sender_id);
+                {
+                    let mut state = state_ref.write().unwrap();
+                    state.neighbours.remove(&parcel.sender_id);
+                    state.pool.remove(parcel.sender_id);
                 }
-                Type::ExtAddrReq => {
-                    info!("Got ExtAddrReq with id {} from node {}", parcel.id, parcel.sender_id);
-                    let addr = stream.peer_addr().unwrap();
-                    let res = ProtoParcel::ext_addr_res(addr);
-                    write_parcel(&mut stream, &res);
+                engine.forget(parcel.sender_id);
+                state_ref.read().unwrap().membership.forget(parcel.sender_id);
+            } else {
+                state_ref.write().unwrap().neighbours.entry(parcel.sender_id).and_modify(|node| {
+                    node.mode = mode
+                });
+            }
+            Some(ProtoParcel::ack(parcel.id))
+        }
+        Body::AddNode { nodes } => {
+            info!("Received AddNode with id {} from node {}", parcel.id, parcel.sender_id);
+            let restarted: Vec<Uuid> = {
+                let mut state = state_ref.write().unwrap();
+                let restarted: Vec<Uuid> = nodes.iter().filter(|node| state.neighbours.contains_key(&node.id)).map(|node| node.id).collect();
+                for node in nodes {
+                    state.add_neighbour(node);
                 }
-                _ => {
-                    error!("Unexpected message type!, {}", parcel.parcel_type);
-                    return;
+                for id in &restarted {
+                    state.pool.remove(*id);
                 }
+                restarted
+            };
+            // a node announced again has restarted
+            for id in restarted {
+                info!("Node {} restarted, rejoining", id);
+                engine.forget(id);
+                state_ref.read().unwrap().membership.forget(id);
             }
-        });
+            Some(ProtoParcel::ack(parcel.id))
+        }
+        Body::ResourceRequest { .. } | Body::ResourceRelease { .. } | Body::ResourceRequestBatch { .. } |
+        Body::ResourceReleaseBatch { .. } | Body::VoteReq { .. } | Body::AppendReq { .. } | Body::Forward { .. } => {
+            // only members of the cluster take part in ordering
+            if !state_ref.read().unwrap().neighbours.contains_key(&parcel.sender_id) {
+                error!("Refusing {} from unknown node {}", parcel.parcel_type(), parcel.sender_id);
+                return Some(ProtoParcel::error(ProtoError::UnknownSender, format!("{} isn't a member of the cluster", parcel.sender_id), parcel.id));
+            }
+            engine.handle(parcel).await
+        }
+        Body::ExtAddrReq => {
+            info!("Got ExtAddrReq with id {} from node {}", parcel.id, parcel.sender_id);
+            Some(ProtoParcel::ext_addr_res(peer_addr))
+        }
+        body => {
+            error!("Unexpected message type!, {}", body.parcel_type());
+            Some(ProtoParcel::error(ProtoError::UnexpectedType, format!("{} isn't a request", body.parcel_type()), parcel.id))
+        }
     }
 }
+
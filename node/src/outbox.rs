@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::sync::futures::Notified;
+use futures::future::join_all;
+
+use log::debug;
+
+use crate::proto::ResourceRelease;
+use crate::req::publish::pub_rel_to;
+use crate::state::State;
+use uuid::Uuid;
+
+/// Releases held back for each neighbour when releases are deferred.
+///
+/// Leaving the critical section doesn't send anything. The releases wait here until they can be
+/// handed to a neighbour along with a grant of one of its requests, which is what lets a single
+/// message both grant and release. Whatever is still waiting after the linger is sent by `flush`.
+pub struct ReleaseOutbox {
+    pending: Mutex<HashMap<Uuid, Vec<ResourceRelease>>>,
+    notify: Notify,
+    exits: Notify,
+    taken: Notify,
+}
+
+impl ReleaseOutbox {
+    pub fn new() -> ReleaseOutbox {
+        ReleaseOutbox { pending: Mutex::new(HashMap::new()), notify: Notify::new(), exits: Notify::new(), taken: Notify::new() }
+    }
+
+    /// Holds `releases` back for every node in `neighbours`, in the order given.
+    pub fn defer(&self, neighbours: &[Uuid], releases: &[ResourceRelease]) {
+        if !neighbours.is_empty() && !releases.is_empty() {
+            let mut pending = self.pending.lock().unwrap();
+            for id in neighbours {
+                pending.entry(*id).or_default().extend_from_slice(releases);
+            }
+            self.notify.notify_one();
+        }
+        self.exits.notify_waiters();
+    }
+
+    /// Waits until `waiting` no longer holds, checking again whenever releases are deferred.
+    pub async fn wait_for_exits(&self, waiting: impl Fn() -> bool) {
+        loop {
+            let exited = self.exits.notified();
+            tokio::pin!(exited);
+            exited.as_mut().enable(); // register before checking so no exit is missed
+
+            if !waiting() {
+                return;
+            }
+            exited.await;
+        }
+    }
+
+    /// Whether no release is held back.
+    pub fn is_empty(&self) -> bool {
+        self.pending.lock().unwrap().is_empty()
+    }
+
+    /// Resolves the next time releases are taken out, by a grant or a flush.
+    pub fn taken(&self) -> Notified<'_> {
+        self.taken.notified()
+    }
+
+    /// Takes every release held back for node `id`.
+    pub fn take(&self, id: Uuid) -> Vec<ResourceRelease> {
+        let releases = self.pending.lock().unwrap().remove(&id).unwrap_or_default();
+        self.taken.notify_waiters();
+        releases
+    }
+
+    fn take_all(&self) -> HashMap<Uuid, Vec<ResourceRelease>> {
+        let releases = std::mem::take(&mut *self.pending.lock().unwrap());
+        self.taken.notify_waiters();
+        releases
+    }
+}
+
+impl Default for ReleaseOutbox {
+    fn default() -> Self {
+        ReleaseOutbox::new()
+    }
+}
+
+/// Sends releases that weren't picked up by a grant within `linger` of being deferred.
+pub async fn flush(state: Arc<RwLock<State>>, linger: Duration) {
+    let outbox = state.read().unwrap().outbox.clone();
+
+    loop {
+        outbox.notify.notified().await;
+        tokio::time::sleep(linger).await;
+
+        flush_now(&state).await;
+    }
+}
+
+/// Sends every release held back right away.
+pub async fn flush_now(state: &Arc<RwLock<State>>) {
+    let sends: Vec<_> = {
+        let state_ref = state.read().unwrap();
+        state_ref.outbox.take_all().into_iter()
+            .filter_map(|(id, rels)| state_ref.neighbours.get(&id).map(|node| (state_ref.pool.get(node), rels)))
+            .collect()
+    }; // release state lock before network calls
+
+    debug!("Flushing deferred releases to {} neighbours", sends.len());
+    // begin parallel scope
+    join_all(sends.into_iter().map(|(conn, rels)| async move { pub_rel_to(&conn, rels).await })).await;
+    // end parallel scope
+}
@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Suspicion level at which a member is suspected unless configured otherwise.
+pub const DEFAULT_PHI_THRESHOLD: f64 = 8.0;
+
+// Most recent intervals the statistics are taken over
+const WINDOW: usize = 100;
+// Until this many intervals were seen, the missing ones are taken to be the estimate
+const PRIOR: usize = 5;
+
+/// When a member was heard from, for a phi-accrual failure detector.
+///
+/// Rather than counting missed heartbeats, the intervals between hearing from a member are taken as
+/// normally distributed, and phi is how unlikely it is to still be waiting given them: a phi of 1 means
+/// a 10% chance the member is still alive, 2 means 1%, and so on. Slow or jittery links widen the
+/// distribution, so they take longer to be suspected than quiet and regular ones.
+pub struct Arrivals {
+    // when it was last heard from, or first known of
+    last: Instant,
+    heard: bool,
+    intervals: VecDeque<Duration>,
+}
+
+impl Arrivals {
+    pub fn new() -> Arrivals {
+        Arrivals { last: Instant::now(), heard: false, intervals: VecDeque::new() }
+    }
+
+    /// The member was heard from.
+    pub fn heartbeat(&mut self) {
+        let now = Instant::now();
+        if self.heard {
+            if self.intervals.len() == WINDOW {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(now - self.last);
+        }
+        self.last = now;
+        self.heard = true;
+    }
+
+    /// Suspicion level of the member now. Intervals are expected to be about `estimate` until enough
+    /// of them were seen.
+    pub fn phi(&self, estimate: Duration) -> f64 {
+        self.phi_after(estimate, self.last.elapsed())
+    }
+
+    // Suspicion level of the member once `elapsed` went by since it was last heard from
+    pub(crate) fn phi_after(&self, estimate: Duration, elapsed: Duration) -> f64 {
+        let prior = std::iter::repeat_n(estimate, PRIOR.saturating_sub(self.intervals.len()));
+        let samples: Vec<f64> = self.intervals.iter().cloned().chain(prior).map(|interval| interval.as_secs_f64()).collect();
+
+        let count = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / count;
+        let variance = samples.iter().map(|interval| (interval - mean).powi(2)).sum::<f64>() / count;
+        // too regular intervals would make the smallest delay look fatal
+        let std_dev = variance.sqrt().max(mean / 4.0);
+
+        phi(elapsed.as_secs_f64(), mean, std_dev)
+    }
+}
+
+impl Default for Arrivals {
+    fn default() -> Self {
+        Arrivals::new()
+    }
+}
+
+// -log10 of the chance of an interval longer than `elapsed`, using a logistic approximation of the
+// normal distribution's tail
+fn phi(elapsed: f64, mean: f64, std_dev: f64) -> f64 {
+    let y = (elapsed - mean) / std_dev;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
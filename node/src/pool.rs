@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+use log::{debug, error, info};
+
+use crate::error::{Error, Result};
+use crate::net::{connect, read_parcel, write_frame, encode_parcel, get_timeouts, CHUNKED};
+use crate::proto::{ProtoParcel, Version, PROTO_VERSION};
+use crate::state::Node;
+use uuid::Uuid;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<ProtoParcel>>>>;
+// An encoded parcel waiting to be written, along with its id
+type Outgoing = (u64, Vec<u8>);
+
+/// A long-lived connection to a single neighbour.
+///
+/// Parcels are written to one TCP stream and responses are matched to their requests by
+/// `ProtoParcel::id`, so any number of requests can be in flight at once. A background task owns
+/// the stream and reconnects with exponential backoff whenever it is lost. Requests that are
+/// queued or unanswered when the stream fails are failed instead of being retried, and so are those
+/// left unanswered for longer than the request timeout.
+pub struct Connection {
+    addr: SocketAddr,
+    // protocol version spoken to the neighbour
+    version: Version,
+    outgoing: UnboundedSender<Outgoing>,
+    pending: Pending,
+}
+
+impl Connection {
+    /// Opens a connection to `addr`, speaking protocol `version`. Must be called from within the
+    /// tokio runtime.
+    pub fn open(addr: SocketAddr, version: Version) -> Connection {
+        let (outgoing, rx) = mpsc::unbounded_channel();
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+
+        tokio::spawn(run(addr, rx, pending.clone(), version >= CHUNKED));
+
+        Connection { addr, version, outgoing, pending }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Whether the background task has stopped and the connection can no longer be used.
+    pub fn is_closed(&self) -> bool {
+        self.outgoing.is_closed()
+    }
+
+    /// Sends `parcel` and waits for the response carrying the same id, failing with `Error::Timeout`
+    /// if it doesn't come in time. A parcel over the size limit isn't sent.
+    pub async fn request(&self, mut parcel: ProtoParcel) -> Result<ProtoParcel> {
+        parcel.proto_version = self.version.to_string();
+        let buf = encode_parcel(&parcel)?;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(parcel.id, tx);
+
+        if self.outgoing.send((parcel.id, buf)).is_err() {
+            return Err(connection_lost(self.addr));
+        }
+
+        match tokio::time::timeout(get_timeouts().request, rx).await {
+            Ok(response) => response.map_err(|_| connection_lost(self.addr)),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&parcel.id);
+                Err(Error::Timeout("Request"))
+            }
+        }
+    }
+}
+
+fn connection_lost(addr: SocketAddr) -> Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, format!("Connection to {} lost", addr)).into()
+}
+
+// Drops every queued parcel along with its waiter.
+fn drain(outgoing: &mut UnboundedReceiver<Outgoing>, pending: &Pending) {
+    while let Ok((id, _)) = outgoing.try_recv() {
+        pending.lock().unwrap().remove(&id);
+    }
+}
+
+// Drops every waiter, failing the requests that were written but never answered.
+fn fail_pending(pending: &Pending) {
+    pending.lock().unwrap().clear();
+}
+
+// Fails every waiter once the connection task exits, including when it panics.
+struct Waiters(Pending);
+
+impl Drop for Waiters {
+    fn drop(&mut self) {
+        fail_pending(&self.0);
+    }
+}
+
+async fn run(addr: SocketAddr, mut outgoing: UnboundedReceiver<Outgoing>, pending: Pending, chunked: bool) {
+    let _waiters = Waiters(pending.clone());
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let stream = match connect(&addr).await {
+            Ok(stream) => {
+                info!("Connected to {}", addr);
+                // parcels are small request/response pairs, don't let Nagle hold them back
+                if let Err(err) = stream.set_nodelay(true) {
+                    error!("{}: {}", err, addr);
+                }
+                backoff = MIN_BACKOFF;
+                stream
+            }
+            Err(err) => {
+                debug!("{}: {}, retrying in {:?}", err, addr, backoff);
+                drain(&mut outgoing, &pending);
+
+                // fail anything sent while waiting instead of holding it until the next attempt
+                let retry = tokio::time::sleep(backoff);
+                tokio::pin!(retry);
+                loop {
+                    tokio::select! {
+                        _ = &mut retry => break,
+                        parcel = outgoing.recv() => match parcel {
+                            Some((id, _)) => drop(pending.lock().unwrap().remove(&id)),
+                            None => return,
+                        }
+                    }
+                }
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+                continue;
+            }
+        };
+
+        let (mut reader, mut writer) = tokio::io::split(stream);
+
+        let closed = {
+            let reading = async {
+                loop {
+                    match read_parcel(&mut reader).await {
+                        Ok(parcel) => {
+                            let waiter = pending.lock().unwrap().remove(&parcel.id);
+                            match waiter {
+                                Some(waiter) => drop(waiter.send(parcel)),
+                                None => debug!("Dropping unsolicited parcel {} from {}", parcel.id, addr),
+                            }
+                        }
+                        Err(err) => {
+                            error!("Lost connection to {}: {}", addr, err);
+                            return;
+                        }
+                    }
+                }
+            };
+
+            // whether every handle to this connection is gone
+            let writing = async {
+                while let Some((_, buf)) = outgoing.recv().await {
+                    if let Err(err) = write_frame(&mut writer, &buf, chunked).await {
+                        error!("Lost connection to {}: {}", addr, err);
+                        return false;
+                    }
+                }
+                true
+            };
+
+            tokio::select! {
+                _ = reading => false,
+                closed = writing => closed,
+            }
+        };
+
+        if closed {
+            // every handle to this connection is gone
+            return;
+        }
+
+        drain(&mut outgoing, &pending);
+        fail_pending(&pending);
+    }
+}
+
+/// Connections to neighbours, keyed by node id.
+pub struct ConnectionPool {
+    connections: Mutex<HashMap<Uuid, Arc<Connection>>>,
+}
+
+impl ConnectionPool {
+    pub fn new() -> ConnectionPool {
+        ConnectionPool { connections: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns the connection to `node`, opening one if there is none or if the node has moved or
+    /// was upgraded. The connection speaks the newest protocol version both sides do.
+    pub fn get(&self, node: &Node) -> Arc<Connection> {
+        let version = PROTO_VERSION.min(node.version);
+        let mut connections = self.connections.lock().unwrap();
+        match connections.get(&node.id) {
+            Some(connection) if connection.addr() == node.external_addr && connection.version == version && !connection.is_closed() => connection.clone(),
+            _ => {
+                let connection = Arc::new(Connection::open(node.external_addr, version));
+                connections.insert(node.id, connection.clone());
+                connection
+            }
+        }
+    }
+
+    /// Closes the connection to node `id`, if any.
+    pub fn remove(&self, id: Uuid) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        ConnectionPool::new()
+    }
+}
@@ -4,7 +4,8 @@ use crate::state::{Node, Mode};
 
 use std::fmt::Display;
 
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::{self, IgnoredAny, IntoDeserializer, MapAccess, Visitor};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use rand::{random};
@@ -15,25 +16,184 @@ use chrono::{DateTime, Utc, Timelike};
 use std::cmp::Ordering;
 
 use std::net::SocketAddr;
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::legacy;
 use sha2::{Sha256, Digest};
 use std::convert::TryInto;
+use std::str::FromStr;
 use sha2::digest::DynDigest;
 
 
+/// Newest protocol version this node speaks.
+pub const PROTO_VERSION: Version = Version { major: 1, minor: 8 };
+/// Oldest protocol version this node still speaks. Keeping it below `PROTO_VERSION` while a new
+/// version rolls out lets upgraded nodes talk to the ones that weren't upgraded yet.
+pub const MIN_PROTO_VERSION: Version = Version { major: 1, minor: 6 };
+
 lazy_static! {
-    // WIP :/
-    pub static ref PROTO_VERSION: String = "1.1".to_string();
-    pub static ref SENDER: Mutex<u16> = Mutex::new(0);
+    pub static ref SENDER: Mutex<Uuid> = Mutex::new(Uuid::nil());
+    pub static ref RELEASE_MODE: Mutex<ReleaseMode> = Mutex::new(ReleaseMode::Broadcast);
+    pub static ref ENGINE: Mutex<EngineKind> = Mutex::new(EngineKind::Lamport);
+    pub static ref MAX_PARCEL_SIZE: Mutex<usize> = Mutex::new(DEFAULT_MAX_PARCEL_SIZE);
 }
+
+/// Largest parcel, in bytes, a node sends or takes unless configured otherwise.
+pub const DEFAULT_MAX_PARCEL_SIZE: usize = 16 * 1024 * 1024;
 const PRIME_ONE: u64 = 2999085892127319403;
 const PRIME_TWO: u64 = 13962674565864582377;
 const PRIME_THREE: u64 = 13714677094544069263;
 
 pub fn get_proto_version() -> String {
-    return PROTO_VERSION.clone();
+    PROTO_VERSION.to_string()
+}
+
+/// A protocol version. Versions with the same major number can be spoken by the same node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl Version {
+    /// Whether this node speaks `version`.
+    pub fn supported(version: &str) -> bool {
+        Version::from_str(version).is_ok_and(|version| MIN_PROTO_VERSION <= version && version <= PROTO_VERSION)
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, minor) = s.split_once('.').ok_or_else(|| format!("Malformed protocol version {}", s))?;
+        match (major.parse(), minor.parse()) {
+            (Ok(major), Ok(minor)) => Ok(Version { major, minor }),
+            _ => Err(format!("Malformed protocol version {}", s)),
+        }
+    }
+}
+
+// Features the whole cluster has to agree on
+const RAFT: &str = "raft";
+const DEFERRED_RELEASE: &str = "deferred-release";
+const CLUSTER_FEATURES: [&str; 2] = [RAFT, DEFERRED_RELEASE];
+
+/// What a node speaks, exchanged during discovery.
+///
+/// Two nodes get along if their version ranges overlap and they agree on the features the whole
+/// cluster has to share, the ordering engine and release mode. Features a node doesn't know of are
+/// ignored, so newer nodes can advertise optional ones.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Handshake {
+    pub min_version: Version,
+    pub max_version: Version,
+    pub features: Vec<String>,
+}
+
+impl Handshake {
+    /// What this node speaks.
+    pub fn local() -> Handshake {
+        let mut features = vec![];
+        if get_engine() == EngineKind::Raft {
+            features.push(RAFT.to_string());
+        }
+        if get_release_mode() == ReleaseMode::Deferred {
+            features.push(DEFERRED_RELEASE.to_string());
+        }
+        Handshake { min_version: MIN_PROTO_VERSION, max_version: PROTO_VERSION, features }
+    }
+
+    /// The newest version both sides speak, or why they can't talk.
+    pub fn negotiate(&self, other: &Handshake) -> Result<Version, ProtoErrorKind> {
+        let version = self.max_version.min(other.max_version);
+        if version < self.min_version.max(other.min_version) {
+            return Err(ProtoErrorKind::VersionMismatch { min: self.min_version, max: self.max_version });
+        }
+        for feature in CLUSTER_FEATURES {
+            let ours = self.features.iter().any(|f| f == feature);
+            if ours != other.features.iter().any(|f| f == feature) {
+                return Err(ProtoErrorKind::FeatureMismatch { feature: feature.to_string(), required: ours });
+            }
+        }
+        Ok(version)
+    }
+}
+
+/// Which ordering engine the cluster runs. Chosen per cluster.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EngineKind {
+    // All-to-all lock protocol over a timestamp ordered queue.
+    Lamport,
+    // Leader based replicated log, committed by a majority.
+    Raft,
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lamport" => Ok(EngineKind::Lamport),
+            "raft" => Ok(EngineKind::Raft),
+            _ => Err(format!("Unknown ordering engine {}", s)),
+        }
+    }
 }
 
-pub fn set_sender_id(id: u16) {
+pub fn get_engine() -> EngineKind {
+    *ENGINE.lock().unwrap()
+}
+
+pub fn set_engine(engine: EngineKind) {
+    *ENGINE.lock().unwrap() = engine;
+}
+
+/// How a node tells the cluster it has left its critical section. Chosen per cluster.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReleaseMode {
+    // A release is broadcast to every neighbour right away.
+    Broadcast,
+    // Ricart–Agrawala style. Releases are held back and handed to each neighbour along with the
+    // next grant of one of its requests, or flushed after a linger when none comes.
+    Deferred,
+}
+
+impl FromStr for ReleaseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "broadcast" => Ok(ReleaseMode::Broadcast),
+            "deferred" => Ok(ReleaseMode::Deferred),
+            _ => Err(format!("Unknown release mode {}", s)),
+        }
+    }
+}
+
+pub fn get_release_mode() -> ReleaseMode {
+    *RELEASE_MODE.lock().unwrap()
+}
+
+pub fn set_release_mode(mode: ReleaseMode) {
+    *RELEASE_MODE.lock().unwrap() = mode;
+}
+
+pub fn get_max_parcel_size() -> usize {
+    *MAX_PARCEL_SIZE.lock().unwrap()
+}
+
+pub fn set_max_parcel_size(size: usize) {
+    *MAX_PARCEL_SIZE.lock().unwrap() = size;
+}
+
+pub fn set_sender_id(id: Uuid) {
     let mut _id = SENDER.lock().unwrap();
     *_id = id;
 }
@@ -62,8 +222,9 @@ fn calculate_hash(message: &Vec<u8>, timestamp: &DateTime<Utc>) -> ([u8; 32], u6
 }
 
 // Enumeration over the types of protocol messages
-#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(FromPrimitive, ToPrimitive, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Type {
+    #[serde(alias = "Error")]
     ProtoError = 0,
 
     DscReq = 1,
@@ -86,6 +247,32 @@ pub enum Type {
 
     ExtAddrReq = 12,
     ExtAddrRes = 13,
+
+    ResourceRequestBatch = 14,
+    ResourceReleaseBatch = 15,
+
+    BatchAck = 16,
+
+    Grant = 17,
+
+    VoteReq = 18,
+    VoteRes = 19,
+
+    AppendReq = 20,
+    AppendRes = 21,
+
+    Forward = 22,
+
+    CatchUpReq = 23,
+    CatchUpRes = 24,
+
+    SnapshotReq = 25,
+    SnapshotRes = 26,
+
+    RejoinReq = 27,
+    RejoinRes = 28,
+
+    PingReq = 29,
 }
 
 impl Display for Type {
@@ -106,44 +293,149 @@ impl Display for Type {
 
             Type::ExtAddrRes => write!(f, "{}", "ExtAddrRes"),
             Type::ExtAddrReq => write!(f, "{}", "ExtAddrReq"),
+
+            Type::ResourceRequestBatch => write!(f, "ResourceRequestBatch"),
+            Type::ResourceReleaseBatch => write!(f, "ResourceReleaseBatch"),
+            Type::BatchAck => write!(f, "BatchAck"),
+            Type::Grant => write!(f, "Grant"),
+            Type::VoteReq => write!(f, "VoteReq"),
+            Type::VoteRes => write!(f, "VoteRes"),
+            Type::AppendReq => write!(f, "AppendReq"),
+            Type::AppendRes => write!(f, "AppendRes"),
+            Type::Forward => write!(f, "Forward"),
+            Type::CatchUpReq => write!(f, "CatchUpReq"),
+            Type::CatchUpRes => write!(f, "CatchUpRes"),
+            Type::SnapshotReq => write!(f, "SnapshotReq"),
+            Type::SnapshotRes => write!(f, "SnapshotRes"),
+            Type::RejoinReq => write!(f, "RejoinReq"),
+            Type::RejoinRes => write!(f, "RejoinRes"),
+            Type::PingReq => write!(f, "PingReq"),
         }
     }
 }
 
 // Enumeration over the types of protocol errors.
-#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(FromPrimitive, ToPrimitive, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ProtoError {
-    BadRes = 1,
-    BadReq = 2,
+    // the body couldn't be decoded or doesn't match the parcel type
+    MalformedBody = 1,
+    // the receiver doesn't take parcels of this type
+    UnexpectedType = 2,
+    VersionMismatch = 3,
+    FeatureMismatch = 4,
+    ClusterMismatch = 5,
+    IdTaken = 6,
+    // the sender isn't a member of the receiver's cluster
+    UnknownSender = 7,
+    // the receiver has too many requests in flight on the connection
+    Overloaded = 8,
+    // the receiver can't serve the request right now
+    Unavailable = 9,
+    // the response wouldn't fit in a parcel
+    TooLarge = 10,
+    // the parcel wasn't sealed with the cluster's secret, or was sent in the name of another node
+    Unauthenticated = 11,
+}
 
+impl Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtoError::MalformedBody => write!(f, "malformed body"),
+            ProtoError::UnexpectedType => write!(f, "unexpected type"),
+            ProtoError::VersionMismatch => write!(f, "version mismatch"),
+            ProtoError::FeatureMismatch => write!(f, "feature mismatch"),
+            ProtoError::ClusterMismatch => write!(f, "cluster mismatch"),
+            ProtoError::IdTaken => write!(f, "id taken"),
+            ProtoError::UnknownSender => write!(f, "unknown sender"),
+            ProtoError::Overloaded => write!(f, "overloaded"),
+            ProtoError::Unavailable => write!(f, "unavailable"),
+            ProtoError::TooLarge => write!(f, "too large"),
+            ProtoError::Unauthenticated => write!(f, "unauthenticated"),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+// The kind of a parcel is told by its body, each variant is named after its `Type`
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Body {
-    Empty,
-
     DscReq {
-        identity: Node
+        identity: Node,
+        cluster: Cluster,
+        handshake: Handshake,
     },
 
     DscRes {
         self_id: Node,
         neighbours: Vec<Node>,
+        cluster: Cluster,
+        handshake: Handshake,
+    },
+
+    // Why the request with id `in_reply_to` failed, `detail` is meant for people
+    Error {
+        code: ProtoError,
+        detail: String,
+        in_reply_to: u64,
     },
 
+    SeqReq,
+
     SeqRes {
-        seq_number: u8
+        seq_number: u64
     },
 
     AddNode {
         nodes: Vec<Node>
     },
 
+    // Asks for up to `limit` delivered messages following sequence number `after`
+    CatchUpReq {
+        after: u64,
+        limit: u32,
+    },
+
+    CatchUpRes {
+        messages: Vec<MessageWrapper>,
+    },
+
+    SnapshotReq,
+
+    SnapshotRes {
+        snapshot: Snapshot,
+    },
+
+    // Failure detector probes, carrying membership updates
+    Ping {
+        updates: Vec<Gossip>,
+    },
+
+    Pong {
+        updates: Vec<Gossip>,
+    },
+
+    // Asks the receiver to ping `target` on the sender's behalf, answered with a `Pong` if it got through
+    PingReq {
+        target: Uuid,
+        timeout_ms: u64,
+        updates: Vec<Gossip>,
+    },
+
+    // A timed out node asking to be taken back, with the sequence number it got to
+    RejoinReq {
+        identity: Node,
+        sequence: u64,
+    },
+
+    RejoinRes {
+        sequence: u64,
+    },
+
     StateChange {
         mode: Mode
     },
 
-    Publish {
+    // A publish handed to the leader
+    Forward {
         message: Vec<u8>,
     },
 
@@ -155,6 +447,17 @@ pub enum Body {
         resource_release: ResourceRelease
     },
 
+    // Requests/releases coalesced by the sender, in timestamp order
+    ResourceRequestBatch {
+        resource_requests: Vec<ResourceRequest>
+    },
+
+    ResourceReleaseBatch {
+        resource_releases: Vec<ResourceRelease>
+    },
+
+    ExtAddrReq,
+
     ExtAddrRes {
         addr: SocketAddr
     },
@@ -162,18 +465,207 @@ pub enum Body {
     Ack {
         message_id: u64
     },
+
+    // Acknowledges the first `accepted` requests of a batch
+    BatchAck {
+        message_id: u64,
+        accepted: usize,
+    },
+
+    // Acknowledges requests with deferred releases, carrying the releases held back for the receiver
+    Grant {
+        message_id: u64,
+        accepted: usize,
+        resource_releases: Vec<ResourceRelease>,
+    },
+
+    // Raft engine. The candidate and the leader are the sender of the parcel.
+    VoteReq {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+
+    VoteRes {
+        term: u64,
+        granted: bool,
+    },
+
+    AppendReq {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+
+    // On failure `match_index` is where the leader should try again from
+    AppendRes {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+}
+
+impl Body {
+    pub fn parcel_type(&self) -> Type {
+        match self {
+            Body::DscReq { .. } => Type::DscReq,
+            Body::DscRes { .. } => Type::DscRes,
+            Body::Error { .. } => Type::ProtoError,
+            Body::SeqReq => Type::SeqReq,
+            Body::SeqRes { .. } => Type::SeqRes,
+            Body::AddNode { .. } => Type::AddNode,
+            Body::CatchUpReq { .. } => Type::CatchUpReq,
+            Body::CatchUpRes { .. } => Type::CatchUpRes,
+            Body::SnapshotReq => Type::SnapshotReq,
+            Body::SnapshotRes { .. } => Type::SnapshotRes,
+            Body::Ping { .. } => Type::Ping,
+            Body::Pong { .. } => Type::Pong,
+            Body::PingReq { .. } => Type::PingReq,
+            Body::RejoinReq { .. } => Type::RejoinReq,
+            Body::RejoinRes { .. } => Type::RejoinRes,
+            Body::StateChange { .. } => Type::StateChange,
+            Body::Forward { .. } => Type::Forward,
+            Body::ResourceRequest { .. } => Type::ResourceRequest,
+            Body::ResourceRelease { .. } => Type::ResourceRelease,
+            Body::ResourceRequestBatch { .. } => Type::ResourceRequestBatch,
+            Body::ResourceReleaseBatch { .. } => Type::ResourceReleaseBatch,
+            Body::ExtAddrReq => Type::ExtAddrReq,
+            Body::ExtAddrRes { .. } => Type::ExtAddrRes,
+            Body::Ack { .. } => Type::Ack,
+            Body::BatchAck { .. } => Type::BatchAck,
+            Body::Grant { .. } => Type::Grant,
+            Body::VoteReq { .. } => Type::VoteReq,
+            Body::VoteRes { .. } => Type::VoteRes,
+            Body::AppendReq { .. } => Type::AppendReq,
+            Body::AppendRes { .. } => Type::AppendRes,
+        }
+    }
+}
+
+// An entry of the log replicated by the raft engine. A leader starts its term with an entry
+// carrying no message.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub term: u64,
+    pub owner: Uuid,
+    pub message: Option<Vec<u8>>,
+    // set on entries that change the configuration, to every member of the new one
+    #[serde(default)]
+    pub members: Option<Vec<Uuid>>,
+}
+
+// How healthy a member looks to the failure detector
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Health {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+// A change in a member's health, spread on the back of pings. A member refutes suspicion of itself
+// by raising its incarnation, which overrides anything said about earlier ones.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Gossip {
+    pub id: Uuid,
+    pub health: Health,
+    pub incarnation: u64,
+}
+
+// The cluster a node belongs to. The id is picked by the node that formed the cluster and learnt by
+// the others as they join, a node that hasn't joined yet has none.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Cluster {
+    pub name: String,
+    pub id: Option<Uuid>,
+}
+
+impl Cluster {
+    // Whether a node of `other` may join this cluster
+    pub fn accepts(&self, other: &Cluster) -> bool {
+        self.name == other.name && match (self.id, other.id) {
+            (Some(id), Some(other_id)) => id == other_id,
+            _ => true,
+        }
+    }
+}
+
+impl Display for Cluster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.id {
+            Some(id) => write!(f, "{} ({})", self.name, id),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// Why a request was refused
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ProtoErrorKind {
+    // the sender speaks none of the protocol versions the receiver does, `min` to `max`
+    VersionMismatch {
+        min: Version,
+        max: Version,
+    },
+    // the receiver's cluster does or doesn't use `feature`, and the sender has to match
+    FeatureMismatch {
+        feature: String,
+        required: bool,
+    },
+    // the sender belongs to another cluster
+    ClusterMismatch,
+    // the sender's id belongs to a node at another address
+    IdTaken,
+}
+
+impl ProtoErrorKind {
+    pub fn code(&self) -> ProtoError {
+        match self {
+            ProtoErrorKind::VersionMismatch { .. } => ProtoError::VersionMismatch,
+            ProtoErrorKind::FeatureMismatch { .. } => ProtoError::FeatureMismatch,
+            ProtoErrorKind::ClusterMismatch => ProtoError::ClusterMismatch,
+            ProtoErrorKind::IdTaken => ProtoError::IdTaken,
+        }
+    }
+}
+
+impl Display for ProtoErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtoErrorKind::VersionMismatch { min, max } => write!(f, "unsupported protocol version, expected {} to {}", min, max),
+            ProtoErrorKind::FeatureMismatch { feature, required: true } => write!(f, "the cluster requires {}", feature),
+            ProtoErrorKind::FeatureMismatch { feature, required: false } => write!(f, "the cluster doesn't use {}", feature),
+            ProtoErrorKind::ClusterMismatch => write!(f, "different cluster"),
+            ProtoErrorKind::IdTaken => write!(f, "node id already taken"),
+        }
+    }
+}
+
+// Point-in-time state of a node, for a joining node to start from
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    // sequence number of the last delivered message
+    pub sequence: u64,
+    // sequence number of the oldest message in `messages`, the log can be tailed from there
+    pub log_start: u64,
+    // the newest messages of the sender's log, up to `sequence`
+    pub messages: Vec<MessageWrapper>,
+    // sequence number of the last message consumed, per client
+    pub offsets: HashMap<u64, u64>,
+    pub members: Vec<Node>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct MessageWrapper {
     pub message: Vec<u8>,
-    pub sequence: u16,
+    pub sequence: u64,
     pub receiver_mask: u32,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ResourceRequest {
-    pub owner: u16,
+    pub owner: Uuid,
     pub message_hash: [u8; 32],
     pub shorthand: u64,
     pub timestamp: DateTime<Utc>,
@@ -192,15 +684,16 @@ impl PartialOrd for ResourceRequest {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ResourceRelease {
-    pub owner: u16,
+    pub owner: Uuid,
     pub message_hash: [u8; 32],
     pub shorthand: u64,
     pub timestamp: DateTime<Utc>,
     pub message: MessageWrapper,
     pub local: bool,
-    pub sequence: u16,
+    // cluster-wide position, stamped when the message leaves the critical section
+    pub sequence: u64,
 }
 
 impl ResourceRequest {
@@ -241,73 +734,187 @@ pub enum Pledge {
 }
 
 
-#[derive(Serialize, Deserialize)]
+// The part of a parcel every protocol version agrees on, read before the rest so that a parcel in a
+// version the receiver doesn't speak can be refused rather than failing to decode
+#[derive(Deserialize)]
+pub struct ParcelHeader {
+    pub id: u64,
+    pub proto_version: String,
+    // none for bodies only older versions send
+    #[serde(rename = "body", deserialize_with = "body_type")]
+    pub parcel_type: Option<Type>,
+}
+
+// Reads the kind of a body from its variant name, skipping its contents
+fn body_type<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Type>, D::Error> {
+    struct Tag;
+
+    impl<'de> Visitor<'de> for Tag {
+        type Value = Option<Type>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a parcel body")
+        }
+
+        fn visit_str<E: de::Error>(self, tag: &str) -> Result<Option<Type>, E> {
+            let tag: de::value::StrDeserializer<E> = tag.into_deserializer();
+            Ok(Type::deserialize(tag).ok())
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Option<Type>, A::Error> {
+            let tag: String = map.next_key()?.ok_or_else(|| de::Error::custom("empty body"))?;
+            map.next_value::<IgnoredAny>()?;
+            self.visit_str(&tag)
+        }
+    }
+
+    deserializer.deserialize_any(Tag)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ProtoParcel {
     // id of message
     pub id: u64,
     pub proto_version: String,
     // id of sender
-    pub sender_id: u16,
+    pub sender_id: Uuid,
     // whether or not packet is a response
     pub is_response: bool,
-    // type of packet
-    pub parcel_type: Type,
-    // message body
+    // message body, which tells the type of packet
     pub body: Body,
 }
 
 impl ProtoParcel {
-    pub fn dsc_req(self_node_information: Node) -> ProtoParcel {
+    pub fn parcel_type(&self) -> Type {
+        self.body.parcel_type()
+    }
+
+    /// The parcel in the wire format of the version it's stamped with.
+    pub fn encode(&self) -> Result<Vec<u8>, serde_cbor::Error> {
+        if legacy::is_legacy(&self.proto_version) {
+            legacy::encode(self)
+        } else {
+            serde_cbor::to_vec(self)
+        }
+    }
+
+    /// Reads a parcel in the wire format of whichever version it was written in.
+    pub fn decode(buf: &[u8]) -> Result<ProtoParcel, serde_cbor::Error> {
+        let header: ParcelHeader = serde_cbor::from_slice(buf)?;
+        if legacy::is_legacy(&header.proto_version) {
+            legacy::decode(buf)
+        } else {
+            serde_cbor::from_slice(buf)
+        }
+    }
+
+    pub fn dsc_req(self_node_information: Node, cluster: Cluster) -> ProtoParcel {
+        // the handshake tells which versions the sender speaks, the request itself has to be readable by all
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: MIN_PROTO_VERSION.to_string(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: false,
-            parcel_type: Type::DscReq,
-            body: Body::DscReq { identity: self_node_information },
+            body: Body::DscReq { identity: self_node_information, cluster, handshake: Handshake::local() },
         }
     }
 
-    pub fn dsc_res(neighbours_information: Vec<Node>, self_id: Node) -> ProtoParcel {
+    pub fn dsc_res(neighbours_information: Vec<Node>, self_id: Node, cluster: Cluster) -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: true,
-            parcel_type: Type::DscRes,
-            body: Body::DscRes { neighbours: neighbours_information, self_id },
+            body: Body::DscRes { neighbours: neighbours_information, self_id, cluster, handshake: Handshake::local() },
         }
     }
 
     pub fn seq_req() -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: false,
-            parcel_type: Type::SeqReq,
-            body: Body::Empty,
+            body: Body::SeqReq,
         }
     }
 
-    pub fn seq_res(seq_number: u8) -> ProtoParcel {
+    pub fn seq_res(seq_number: u64) -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: true,
-            parcel_type: Type::SeqRes,
             body: Body::SeqRes { seq_number },
         }
     }
 
+    pub fn catch_up_req(after: u64, limit: u32) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: false,
+            body: Body::CatchUpReq { after, limit },
+        }
+    }
+
+    pub fn catch_up_res(messages: Vec<MessageWrapper>) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: true,
+            body: Body::CatchUpRes { messages },
+        }
+    }
+
+    pub fn snapshot_req() -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: false,
+            body: Body::SnapshotReq,
+        }
+    }
+
+    pub fn snapshot_res(snapshot: Snapshot) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: true,
+            body: Body::SnapshotRes { snapshot },
+        }
+    }
+
+    pub fn rejoin_req(self_node_information: Node, sequence: u64) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: false,
+            body: Body::RejoinReq { identity: self_node_information, sequence },
+        }
+    }
+
+    pub fn rejoin_res(sequence: u64) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: true,
+            body: Body::RejoinRes { sequence },
+        }
+    }
+
     pub fn add_node(nodes: Vec<Node>) -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: true,
-            parcel_type: Type::AddNode,
             body: Body::AddNode { nodes },
         }
     }
@@ -315,53 +922,122 @@ impl ProtoParcel {
     pub fn state_change(mode: Mode) -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: false,
-            parcel_type: Type::StateChange,
             body: Body::StateChange { mode },
         }
     }
 
-    pub fn ping() -> ProtoParcel {
+    pub fn ping(updates: Vec<Gossip>) -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: false,
-            parcel_type: Type::Ping,
-            body: Body::Empty,
+            body: Body::Ping { updates },
         }
     }
 
-    pub fn pong() -> ProtoParcel {
+    pub fn pong(updates: Vec<Gossip>) -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: true,
-            parcel_type: Type::Pong,
-            body: Body::Empty,
+            body: Body::Pong { updates },
+        }
+    }
+
+    pub fn ping_req(target: Uuid, timeout_ms: u64, updates: Vec<Gossip>) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: false,
+            body: Body::PingReq { target, timeout_ms, updates },
         }
     }
 
     pub fn ack(message_id: u64) -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: true,
-            parcel_type: Type::Ack,
             body: Body::Ack { message_id },
         }
     }
+    pub fn batch_ack(message_id: u64, accepted: usize) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: true,
+            body: Body::BatchAck { message_id, accepted },
+        }
+    }
+    pub fn grant(message_id: u64, accepted: usize, resource_releases: Vec<ResourceRelease>) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: true,
+            body: Body::Grant { message_id, accepted, resource_releases },
+        }
+    }
+    pub fn vote_req(term: u64, last_log_index: u64, last_log_term: u64) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: false,
+            body: Body::VoteReq { term, last_log_index, last_log_term },
+        }
+    }
+    pub fn vote_res(term: u64, granted: bool) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: true,
+            body: Body::VoteRes { term, granted },
+        }
+    }
+    pub fn append_req(term: u64, prev_log_index: u64, prev_log_term: u64, entries: Vec<LogEntry>, leader_commit: u64) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: false,
+            body: Body::AppendReq { term, prev_log_index, prev_log_term, entries, leader_commit },
+        }
+    }
+    pub fn append_res(term: u64, success: bool, match_index: u64) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: true,
+            body: Body::AppendRes { term, success, match_index },
+        }
+    }
+    // A publish handed to the leader by a follower
+    pub fn forward(message: Vec<u8>) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: false,
+            body: Body::Forward { message },
+        }
+    }
     pub fn resource_request(resource_request: ResourceRequest) -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: false,
-            parcel_type: Type::ResourceRequest,
             body: Body::ResourceRequest {
                 resource_request
             },
@@ -370,46 +1046,68 @@ impl ProtoParcel {
     pub fn resource_release(resource_release: ResourceRelease) -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: false,
-            parcel_type: Type::ResourceRelease,
             body: Body::ResourceRelease {
                 resource_release
             },
         }
     }
 
+    pub fn resource_request_batch(resource_requests: Vec<ResourceRequest>) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: false,
+            body: Body::ResourceRequestBatch {
+                resource_requests
+            },
+        }
+    }
+    pub fn resource_release_batch(resource_releases: Vec<ResourceRelease>) -> ProtoParcel {
+        ProtoParcel {
+            id: generate_id(),
+            proto_version: get_proto_version(),
+            sender_id: *SENDER.lock().unwrap(),
+            is_response: false,
+            body: Body::ResourceReleaseBatch {
+                resource_releases
+            },
+        }
+    }
+
     pub fn ext_addr_req() -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: false,
-            parcel_type: Type::ResourceRelease,
-            body: Body::Empty,
+            body: Body::ExtAddrReq,
         }
     }
     pub fn ext_addr_res(addr: SocketAddr) -> ProtoParcel {
         ProtoParcel {
             id: generate_id(),
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: false,
-            parcel_type: Type::ResourceRelease,
             body: Body::ExtAddrRes { addr },
         }
     }
-    pub fn proto_error() -> ProtoParcel {
+    pub fn error(code: ProtoError, detail: String, in_reply_to: u64) -> ProtoParcel {
         ProtoParcel {
-            proto_version: PROTO_VERSION.clone(),
+            proto_version: get_proto_version(),
             id: generate_id(),
             sender_id: *SENDER.lock().unwrap(),
             is_response: true,
-            parcel_type: Type::ProtoError,
-            body: Body::Empty,
+            body: Body::Error { code, detail, in_reply_to },
         }
     }
+    pub fn refusal(kind: ProtoErrorKind, in_reply_to: u64) -> ProtoParcel {
+        ProtoParcel::error(kind.code(), kind.to_string(), in_reply_to)
+    }
 }
 
 pub fn generate_id() -> u64 {
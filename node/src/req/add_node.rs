@@ -1,57 +1,30 @@
 /*
 
  */
-use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
 use crate::state::Node;
-use crate::internal::TaskSignal;
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::mpsc;
+use crate::error::Result;
 use crate::proto::{ProtoParcel};
-use rayon::prelude::*;
-use crate::net::{write_parcel, read_parcel, is_acked};
+use crate::pool::Connection;
+use futures::future::join_all;
+use crate::net::is_acked;
 use log::{error, info};
 
-pub fn add_node(neighbour_list: &Vec<SocketAddr>, nodes: Vec<Node>) {
-    let (sender, receiver): (Sender<TaskSignal>, Receiver<TaskSignal>) = mpsc::channel(); // setup channel for results
-
+// Fails with the first error if any of the neighbours didn't take the new nodes
+pub async fn add_node(neighbour_list: &[Arc<Connection>], nodes: Vec<Node>) -> Result<()> {
     let req = ProtoParcel::add_node(nodes);
 
     // begin parallel scope
-    neighbour_list.into_par_iter().for_each_with(sender, |s, addr| {
-        update(&addr, &req, s);
-    });
+    let results = join_all(neighbour_list.iter().map(|conn| update(conn, &req))).await;
     // end parallel scope
 
-    for result in receiver.iter() {
-        match result {
-            TaskSignal::Success => {}
-            TaskSignal::Fail => {}
-            _ => {}
-        }
-    }
+    results.into_iter().collect()
 }
 
-fn update(host: &SocketAddr, req_parcel: &ProtoParcel, tx: &mut Sender<TaskSignal>) {
-    info!("Pushing new neighbours to {}", host);
-    let mut stream = match TcpStream::connect(host) {
-        Ok(stream) => stream,
-        Err(err) => {
-            error!("{}: {}", err, host);
-            return;
-        }
-    };
+async fn update(conn: &Connection, req_parcel: &ProtoParcel) -> Result<()> {
+    info!("Pushing new neighbours to {}", conn.addr());
     let m_id = req_parcel.id;
 
-    write_parcel(&mut stream, &req_parcel);
-
-    let res_parcel = match read_parcel(&mut stream) {
-        Ok(parcel) => parcel,
-        Err(e) => {
-            error!("Invalid parcel! {}", e);
-            return;
-        }
-    };
-
-    let result = is_acked(res_parcel, m_id);
-    tx.send(result).unwrap();
-}
\ No newline at end of file
+    let res_parcel = conn.request(req_parcel.clone()).await.inspect_err(|e| error!("{}: {}", e, conn.addr()))?;
+    is_acked(res_parcel, m_id)
+}
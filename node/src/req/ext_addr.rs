@@ -1,35 +1,60 @@
-use std::net::{SocketAddr, TcpStream};
-use crate::proto::{ProtoParcel, Body};
-use crate::net::{write_parcel, read_parcel};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use tokio::time::timeout;
+use futures::future::join_all;
+use crate::proto::{ProtoParcel, Body, Type};
+use crate::net::{connect, write_parcel, read_reply, get_timeouts};
+use crate::error::{Error, Result};
 
-use log::{error};
+use log::{error, info, warn};
 
-// Returns the route through which the sender is contacted
-pub fn get_ext_addr_from_neighbour(host: &SocketAddr) -> Option<SocketAddr> {
-    let req = ProtoParcel::ext_addr_req();
+/// The address neighbours reach this node on, going by the one most of `neighbour_list` see it
+/// connect from. They only see its ip, it's reached on the `port` it listens on. Fails with the
+/// last error if none of them answered.
+pub async fn detect_ext_addr(neighbour_list: &[SocketAddr], port: u16) -> Result<SocketAddr> {
+    // each neighbour has as long to tell the node its address as any other request
+    let deadline = get_timeouts().request;
+    // begin parallel scope
+    let answers = join_all(neighbour_list.iter().map(|host| async move {
+        timeout(deadline, get_ext_addr_from_neighbour(host)).await.unwrap_or(Err(Error::Timeout("Learning the external address")))
+    })).await;
+    // end parallel scope
 
-    let mut stream = match TcpStream::connect(host) {
-        Ok(stream) => stream,
-        Err(err) => {
-            error!("{}: {}", err, host);
-            return None;
+    let mut seen: HashMap<IpAddr, usize> = HashMap::new();
+    let mut last_error = Error::Membership("no neighbour to learn the external address from".to_string());
+    for answer in answers {
+        match answer {
+            Ok(addr) => *seen.entry(addr.ip()).or_insert(0) += 1,
+            Err(e) => last_error = e,
         }
+    }
+    if seen.len() > 1 {
+        let answers: Vec<String> = seen.iter().map(|(ip, count)| format!("{} ({})", ip, count)).collect();
+        warn!("Neighbours disagree on this node's address: {}", answers.join(", "));
+    }
+
+    let (ip, count) = match seen.into_iter().max_by_key(|(ip, count)| (*count, *ip)) {
+        Some(answer) => answer,
+        None => return Err(last_error),
     };
+    info!("{} of {} neighbours see this node as {}", count, neighbour_list.len(), ip);
+    Ok(SocketAddr::new(ip, port))
+}
 
-    write_parcel(&mut stream, &req);
+// Returns the route through which the sender is contacted
+pub async fn get_ext_addr_from_neighbour(host: &SocketAddr) -> Result<SocketAddr> {
+    let req = ProtoParcel::ext_addr_req();
 
-    match read_parcel(&mut stream) {
-        Ok(res) => {
-            if let Body::ExtAddrRes { addr } = res.body {
-                Some(addr)
-            } else {
-                error!("Body-header type mismatch!");
-                None
-            }
-        }
-        Err(err) => {
-            error!("{}: {}", err, host);
-            None
+    let mut stream = connect(host).await.inspect_err(|err| error!("{}: {}", err, host))?;
+    write_parcel(&mut stream, &req).await.inspect_err(|err| error!("{}: {}", err, host))?;
+
+    let res = read_reply(&mut stream).await.inspect_err(|err| error!("{}: {}", err, host))?;
+    match res.body {
+        Body::ExtAddrRes { addr } => Ok(addr),
+        Body::Error { code, detail, .. } => Err(Error::Protocol { code, detail }),
+        body => {
+            error!("Unexpected response type to ExtAddrReq, {}", body.parcel_type());
+            Err(Error::unexpected(Type::ExtAddrRes, body.parcel_type()))
         }
     }
-}
\ No newline at end of file
+}
@@ -3,4 +3,6 @@ pub mod seq_recovery;
 pub mod add_node;
 pub mod publish;
 pub mod ext_addr;
-pub mod replicate;
\ No newline at end of file
+pub mod replicate;
+pub mod snapshot;
+pub mod rejoin;
\ No newline at end of file
@@ -1,101 +1,161 @@
-use std::net::{SocketAddr, TcpStream};
-use crate::internal::TaskSignal;
-use std::sync::mpsc::{Receiver, Sender};
-use crate::proto::{ResourceRequest, ProtoParcel, ResourceRelease};
-use std::sync::mpsc;
-use rayon::prelude::*;
-use crate::net::{write_parcel, read_parcel, is_acked};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use crate::error::{Error, Result};
+use crate::proto::{ResourceRequest, ProtoParcel, ResourceRelease, Body};
+use futures::future::join_all;
+use tokio::sync::oneshot;
+use tokio::sync::mpsc::UnboundedSender;
+use crate::net::is_acked;
+use crate::pool::Connection;
 use log::{error, debug};
 
-pub fn pub_req(neighbour_list: &Vec<SocketAddr>, req: ResourceRequest) -> TaskSignal {
-    let (sender, receiver): (Sender<TaskSignal>, Receiver<TaskSignal>) = mpsc::channel(); // setup channel for results
+/// Publishes `reqs` to every neighbour and reports each request's outcome to its waiter.
+///
+/// A request succeeds once every neighbour in `neighbour_list` has accepted it and, counting this
+/// node, those make up `quorum` nodes, a majority of the cluster. A neighbour may accept only the
+/// front of a batch, in which case the rest is sent again; this way a request never waits on
+/// the acknowledgement of a later one.
+///
+/// Releases handed over with grants are passed on to `releases`.
+pub async fn pub_req(neighbour_list: &[Arc<Connection>], quorum: usize, reqs: Vec<ResourceRequest>, waiters: Vec<oneshot::Sender<Result<()>>>,
+                     releases: &UnboundedSender<ResourceRelease>) {
+    let mut reqs: Vec<(ResourceRequest, oneshot::Sender<Result<()>>)> = reqs.into_iter().zip(waiters).collect();
+    reqs.sort_by_key(|(req, _)| req.timestamp);
+    let (reqs, waiters): (Vec<ResourceRequest>, Vec<_>) = reqs.into_iter().unzip();
 
-    let req = ProtoParcel::resource_request(req);
+    let tally = Mutex::new(Tally {
+        required: neighbour_list.len(),
+        quorum,
+        acks: vec![0; reqs.len()],
+        waiters: waiters.into_iter().map(Some).collect(),
+    });
+
+    if neighbour_list.is_empty() {
+        // nobody else to ask, which only does for a cluster of one
+        let result = if quorum <= 1 { Ok(()) } else { Err(Error::NoQuorum) };
+        for waiter in tally.lock().unwrap().waiters.iter_mut().filter_map(Option::take) {
+            let _ = waiter.send(result.clone());
+        }
+        return;
+    }
 
     // begin parallel scope
-    neighbour_list.into_par_iter().for_each_with(sender, |s, addr| {
-        publish_request(&addr, &req, s);
-    });
+    join_all(neighbour_list.iter().map(|conn| publish_requests(conn, &reqs, &tally, releases))).await;
     // end parallel scope
+}
+
+// Per-request acknowledgement counts, settling each waiter as soon as its outcome is known.
+struct Tally {
+    required: usize,
+    // acknowledgements needed for a majority, this node included
+    quorum: usize,
+    acks: Vec<usize>,
+    waiters: Vec<Option<oneshot::Sender<Result<()>>>>,
+}
 
-    let total_acks = neighbour_list.len();
-    let mut received_acks: usize = 0;
-    for res in receiver.iter() {
-        match res {
-            TaskSignal::Success => {
-                received_acks += 1;
-                debug!("Acked {}/{}", received_acks, total_acks);
+impl Tally {
+    fn ack(&mut self, range: Range<usize>) {
+        for i in range {
+            self.acks[i] += 1;
+            debug!("Acked {}/{}", self.acks[i], self.required);
 
-                if total_acks == received_acks {
-                    return TaskSignal::Success;
+            if self.acks[i] == self.required {
+                if let Some(waiter) = self.waiters[i].take() {
+                    let result = if self.acks[i] + 1 >= self.quorum { Ok(()) } else { Err(Error::NoQuorum) };
+                    let _ = waiter.send(result);
                 }
             }
-            _ => {
-                return TaskSignal::Fail;
+        }
+    }
+
+    fn fail(&mut self, from: usize, error: Error) {
+        for waiter in self.waiters[from..].iter_mut() {
+            if let Some(waiter) = waiter.take() {
+                let _ = waiter.send(Err(error.clone()));
             }
         }
     }
-    TaskSignal::Fail
 }
 
-fn publish_request(host: &SocketAddr, req_parcel: &ProtoParcel, tx: &mut Sender<TaskSignal>) {
-    debug!("Pushing request to {}", host);
-    let mut stream = match TcpStream::connect(host) {
-        Ok(stream) => stream,
-        Err(err) => {
-            error!("{}: {}", err, host);
-            tx.send(TaskSignal::Fail).unwrap();
-            return;
-        }
-    };
+// Single requests are sent as plain ResourceRequest parcels, anything more as one batch.
+async fn publish_requests(conn: &Connection, reqs: &[ResourceRequest], tally: &Mutex<Tally>, releases: &UnboundedSender<ResourceRelease>) {
+    let mut from = 0;
+    while from < reqs.len() {
+        let req_parcel = if reqs.len() - from == 1 {
+            ProtoParcel::resource_request(reqs[from])
+        } else {
+            ProtoParcel::resource_request_batch(reqs[from..].to_vec())
+        };
+        debug!("Pushing {} requests to {}", reqs.len() - from, conn.addr());
 
-    let m_id = req_parcel.id;
-    write_parcel(&mut stream, &req_parcel);
-
-    let res_parcel = match read_parcel(&mut stream) {
-        Ok(parcel) => parcel,
-        Err(e) => {
-            error!("Invalid parcel! {}", e);
-            tx.send(TaskSignal::Fail).unwrap();
-            return;
-        }
-    };
+        let accepted = match conn.request(req_parcel.clone()).await {
+            Ok(res_parcel) => accepted(res_parcel, req_parcel.id, reqs.len() - from, releases),
+            Err(e) => {
+                error!("{}: {}", e, conn.addr());
+                Err(e)
+            }
+        };
 
-    let result = is_acked(res_parcel, m_id);
-    tx.send(result).unwrap();
+        match accepted {
+            Ok(accepted) if accepted > 0 => {
+                tally.lock().unwrap().ack(from..from + accepted);
+                from += accepted;
+            }
+            Ok(_) => {
+                let error = Error::Ordering(format!("{} accepted none of the requests", conn.addr()));
+                tally.lock().unwrap().fail(from, error);
+                return;
+            }
+            Err(e) => {
+                tally.lock().unwrap().fail(from, e);
+                return;
+            }
+        }
+    }
 }
 
-pub fn pub_rel(neighbour_list: &Vec<SocketAddr>, rel: ResourceRelease) {
-    let (sender, _receiver): (Sender<TaskSignal>, Receiver<TaskSignal>) = mpsc::channel(); // setup channel for results
+// How many of the `sent` requests in parcel `ack_id` the response accepts, or why it accepts none.
+fn accepted(response: ProtoParcel, ack_id: u64, sent: usize, releases: &UnboundedSender<ResourceRelease>) -> Result<usize> {
+    match response.body {
+        Body::BatchAck { message_id, accepted } if message_id == ack_id => Ok(std::cmp::min(accepted, sent)),
+        Body::Grant { message_id, accepted, resource_releases } => {
+            for rel in resource_releases {
+                let _ = releases.send(rel);
+            }
+            Ok(if message_id == ack_id { std::cmp::min(accepted, sent) } else { 0 })
+        }
+        _ => is_acked(response, ack_id).map(|_| 1),
+    }
+}
 
-    let req = ProtoParcel::resource_release(rel);
+// Releases are expected in the order their requests left the queue.
+// Fails with the first error if any of the neighbours didn't take them.
+pub async fn pub_rel(neighbour_list: &[Arc<Connection>], rels: Vec<ResourceRelease>) -> Result<()> {
+    let req = release_parcel(rels);
 
     // begin parallel scope
-    neighbour_list.into_par_iter().for_each_with(sender, |s, addr| {
-        publish_release(&addr, &req, s);
-    });
+    let results = join_all(neighbour_list.iter().map(|conn| publish_release(conn, &req))).await;
     // end parallel scope
+
+    results.into_iter().collect()
 }
 
-fn publish_release(host: &SocketAddr, req_parcel: &ProtoParcel, tx: &mut Sender<TaskSignal>) {
-    debug!("Pushing release to {}", host);
-    let mut stream = match TcpStream::connect(host) {
-        Ok(stream) => stream,
-        Err(err) => {
-            error!("{}: {}", err, host);
-            return;
-        }
-    };
+pub async fn pub_rel_to(conn: &Connection, rels: Vec<ResourceRelease>) -> Result<()> {
+    publish_release(conn, &release_parcel(rels)).await
+}
+
+fn release_parcel(mut rels: Vec<ResourceRelease>) -> ProtoParcel {
+    if rels.len() == 1 {
+        ProtoParcel::resource_release(rels.pop().unwrap())
+    } else {
+        ProtoParcel::resource_release_batch(rels)
+    }
+}
+
+async fn publish_release(conn: &Connection, req_parcel: &ProtoParcel) -> Result<()> {
+    debug!("Pushing release to {}", conn.addr());
     let m_id = req_parcel.id;
-    write_parcel(&mut stream, &req_parcel);
 
-    let res_parcel = match read_parcel(&mut stream) {
-        Ok(parcel) => parcel,
-        Err(e) => {
-            error!("Invalid parcel! {}", e);
-            return;
-        }
-    };
-    let result = is_acked(res_parcel, m_id);
-    tx.send(result).unwrap();
-}
\ No newline at end of file
+    let res_parcel = conn.request(req_parcel.clone()).await.inspect_err(|e| error!("{}: {}", e, conn.addr()))?;
+    is_acked(res_parcel, m_id)
+}
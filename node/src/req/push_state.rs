@@ -1,47 +1,30 @@
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::mpsc;
-use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
 use crate::proto::{ProtoParcel};
-use rayon::prelude::*;
-use crate::net::{write_parcel, read_parcel, is_acked};
+use crate::pool::Connection;
+use futures::future::join_all;
+use crate::net::is_acked;
 use crate::state::Mode;
-use crate::internal::TaskSignal;
+use crate::error::Result;
 use log::{error, info};
 
 /*
     Pushes state update to each host given.
+    Fails with the first error if any of them didn't take it.
  */
-pub fn push_state(neighbour_list: &Vec<SocketAddr>, state: Mode) {
-    let (sender, _receiver): (Sender<TaskSignal>, Receiver<TaskSignal>) = mpsc::channel(); // setup channel for results
-
+pub async fn push_state(neighbour_list: &[Arc<Connection>], state: Mode) -> Result<()> {
     let req = ProtoParcel::state_change(state);
 
     // begin parallel scope
-    neighbour_list.into_par_iter().for_each_with(sender, |s, addr| {
-        update(&addr, &req, s);
-    });
+    let results = join_all(neighbour_list.iter().map(|conn| update(conn, &req))).await;
     // end parallel scope
+
+    results.into_iter().collect()
 }
 
-fn update(host: &SocketAddr, req_parcel: &ProtoParcel, tx: &mut Sender<TaskSignal>) {
-    info!("Pushing update to {}", host);
-    let mut stream = match TcpStream::connect(host) {
-        Ok(stream) => stream,
-        Err(err) => {
-            error!("{}: {}", err, host);
-            return;
-        }
-    };
+async fn update(conn: &Connection, req_parcel: &ProtoParcel) -> Result<()> {
+    info!("Pushing update to {}", conn.addr());
     let m_id = req_parcel.id;
-    write_parcel(&mut stream, &req_parcel);
 
-    let res_parcel = match read_parcel(&mut stream) {
-        Ok(parcel) => parcel,
-        Err(e) => {
-            error!("Invalid parcel! {}", e);
-            return;
-        }
-    };
-    let result = is_acked(res_parcel, m_id);
-    tx.send(result).unwrap();
-}
\ No newline at end of file
+    let res_parcel = conn.request(req_parcel.clone()).await.inspect_err(|e| error!("{}: {}", e, conn.addr()))?;
+    is_acked(res_parcel, m_id)
+}
@@ -0,0 +1,43 @@
+use std::sync::{Arc, RwLock};
+use crate::proto::{ProtoParcel, Body, Type};
+use crate::pool::Connection;
+use crate::state::State;
+use crate::req::{seq_recovery::seq_recovery, replicate::catch_up};
+use crate::error::{Error, Result};
+use log::{error, info, warn};
+
+/*
+    Asks a node that timed out to take us back. Whatever it delivered in the meantime is caught up on
+    first, so that little is left to catch up on once it starts sending us new requests.
+    Fails if it didn't take us back. Messages that couldn't be caught up on don't keep us out.
+ */
+pub async fn rejoin(conn: &Arc<Connection>, state: &Arc<RwLock<State>>) -> Result<()> {
+    info!("Rejoining {}", conn.addr());
+    let (identity, sequence, history) = {
+        let state_ref = state.read().unwrap();
+        (state_ref.get_node_information(), state_ref.sequence.clone(), state_ref.history.clone())
+    }; // release state lock before network calls
+    let hosts = std::slice::from_ref(conn);
+
+    let seq_num = seq_recovery(hosts).await?;
+    if let Err(e) = catch_up(hosts, &sequence, &history, seq_num).await {
+        warn!("{}", e);
+    }
+
+    let req = ProtoParcel::rejoin_req(identity, sequence.current());
+    let res_parcel = conn.request(req).await.inspect_err(|e| error!("{}: {}", e, conn.addr()))?;
+    match res_parcel.body {
+        Body::RejoinRes { sequence: seq_num } => {
+            if let Err(e) = catch_up(hosts, &sequence, &history, seq_num).await {
+                warn!("{}", e);
+            }
+            Ok(())
+        }
+
+        Body::Error { code, detail, .. } => Err(Error::Protocol { code, detail }),
+        body => {
+            error!("Unexpected response type to RejoinReq, {}", body.parcel_type());
+            Err(Error::unexpected(Type::RejoinRes, body.parcel_type()))
+        }
+    }
+}
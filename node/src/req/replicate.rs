@@ -1,43 +1,74 @@
-use std::net::{SocketAddr, TcpStream};
-use crate::proto::{ResourceRelease, ProtoParcel};
-use std::sync::mpsc::{Sender, Receiver};
-use crate::internal::TaskSignal;
-use std::sync::mpsc;
-use rayon::prelude::*;
-use crate::net::{write_parcel, read_parcel, is_acked};
-use log::{error, debug};
-
-pub fn replicate_message(neighbour_list: &Vec<SocketAddr>, _resource: ResourceRelease) {
-    let (sender, _receiver): (Sender<TaskSignal>, Receiver<TaskSignal>) = mpsc::channel(); // setup channel for results
-
-    let req = ProtoParcel::ack(1);
-
-    // begin parallel scope
-    neighbour_list.into_par_iter().for_each_with(sender, |s, addr| {
-        update(&addr, &req, s);
-    });
-    // end parallel scope
+use std::sync::Arc;
+use crate::proto::{ProtoParcel, Body, MessageWrapper, Type};
+use crate::pool::Connection;
+use crate::state::Sequence;
+use crate::history::History;
+use crate::error::{Error, Result};
+use log::{error, debug, info, warn};
+
+// Messages asked for per request
+const PAGE: u32 = 512;
+
+/*
+    Streams in every message after the local sequence number up to `until`, trying each host in
+    turn until one of them has served them all. Messages no host holds anymore are skipped, which
+    is reported as an ordering error once the rest were caught up on.
+ */
+pub async fn catch_up(neighbour_list: &[Arc<Connection>], sequence: &Sequence, history: &History, until: u64) -> Result<()> {
+    let from = sequence.current();
+    if from >= until { return Ok(()); }
+    info!("Catching up on messages {} to {}", from + 1, until);
+
+    for conn in neighbour_list {
+        while sequence.current() < until {
+            let messages = match fetch(conn, sequence.current()).await {
+                Ok(messages) if !messages.is_empty() => messages,
+                _ => break, // try the next host
+            };
+
+            let mut applied = false;
+            for message in messages.into_iter().filter(|message| message.sequence <= until) {
+                let expected = sequence.current() + 1;
+                if message.sequence < expected {
+                    continue;
+                }
+                if message.sequence > expected {
+                    warn!("Messages {} to {} are no longer held by {}", expected, message.sequence - 1, conn.addr());
+                }
+                debug!("Caught up on message {}", message.sequence);
+                sequence.advance_to(message.sequence);
+                history.record(message);
+                applied = true;
+            }
+            if !applied {
+                break; // it would only send the same page again, try the next host
+            }
+        }
+    }
+
+    let skipped = sequence.current() + 1..=until;
+    sequence.advance_to(until);
+    info!("Caught up from sequence number {} to {}", from, until);
+    if skipped.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Ordering(format!("couldn't recover messages {} to {}, skipped them", skipped.start(), skipped.end())))
+    }
 }
 
-fn update(host: &SocketAddr, req_parcel: &ProtoParcel, tx: &mut Sender<TaskSignal>) {
-    debug!("Pushing event to {}", host);
-    let mut stream = match TcpStream::connect(host) {
-        Ok(stream) => stream,
-        Err(err) => {
-            error!("{}: {}", err, host);
-            return;
+async fn fetch(conn: &Connection, after: u64) -> Result<Vec<MessageWrapper>> {
+    debug!("Fetching messages after {} from {}", after, conn.addr());
+
+    let res_parcel = conn.request(ProtoParcel::catch_up_req(after, PAGE)).await.inspect_err(|e| error!("{}: {}", e, conn.addr()))?;
+    match res_parcel.body {
+        Body::CatchUpRes { messages } => {
+            Ok(messages)
         }
-    };
-    let m_id = req_parcel.id;
-    write_parcel(&mut stream, &req_parcel);
-
-    let res_parcel = match read_parcel(&mut stream) {
-        Ok(parcel) => parcel,
-        Err(e) => {
-            error!("Invalid parcel! {}", e);
-            return;
+
+        Body::Error { code, detail, .. } => Err(Error::Protocol { code, detail }),
+        body => {
+            error!("Unexpected response type to CatchUpReq, {}", body.parcel_type());
+            Err(Error::unexpected(Type::CatchUpRes, body.parcel_type()))
         }
-    };
-    let result = is_acked(res_parcel, m_id);
-    tx.send(result).unwrap();
+    }
 }
@@ -1,64 +1,52 @@
-use std::sync::{mpsc};
-use std::sync::mpsc::{Sender, Receiver};
-use rayon::prelude::*;
-use std::net::{SocketAddr, TcpStream};
-use crate::proto::{ProtoParcel, Type, Body};
-use crate::net::{write_parcel, read_parcel};
+use std::sync::Arc;
+use futures::future::join_all;
+use crate::proto::{ProtoParcel, Body, Type};
+use crate::pool::Connection;
+use crate::error::{Error, Result};
 use log::{error, info};
 
 /*
-    Retrieves sequence number from each host provided, returning the largest(most-latest)
+    Retrieves sequence number from each host provided, returning the largest(most-latest),
+    0 if there are none, or the last error if none of them answered
  */
-pub fn seq_recovery(neighbour_list: &Vec<SocketAddr>) -> u8 {
-    if neighbour_list.len() == 0 { return 0; }
-
-    let (sender, receiver): (Sender<u8>, Receiver<u8>) = mpsc::channel(); // setup channel for results
+pub async fn seq_recovery(neighbour_list: &[Arc<Connection>]) -> Result<u64> {
+    if neighbour_list.is_empty() { return Ok(0); }
 
     let req = ProtoParcel::seq_req();
 
     // begin parallel scope
-    neighbour_list.into_par_iter().for_each_with(sender, |s, addr| {
-        recover(&addr, &req, s);
-    });
+    let results = join_all(neighbour_list.iter().map(|conn| recover(conn, &req))).await;
     // end parallel scope
 
-    let max_seq = receiver.iter().max_by_key(|seq| *seq).unwrap();
+    let mut max_seq = None;
+    let mut last_error = None;
+    for result in results {
+        match result {
+            Ok(seq_number) => max_seq = max_seq.max(Some(seq_number)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    let max_seq = match max_seq {
+        Some(max_seq) => max_seq,
+        None => return Err(last_error.unwrap()), // there was at least one host
+    };
     info!("Recovered sequence number {}", max_seq);
-    max_seq
+    Ok(max_seq)
 }
 
-fn recover(host: &SocketAddr, req_parcel: &ProtoParcel, tx: &mut Sender<u8>) {
-    info!("Recovering sequence from {}", host);
+async fn recover(conn: &Connection, req_parcel: &ProtoParcel) -> Result<u64> {
+    info!("Recovering sequence from {}", conn.addr());
 
-    let mut stream = match TcpStream::connect(host) {
-        Ok(stream) => stream,
-        Err(err) => {
-            error!("{}: {}", err, host);
-            return;
-        }
-    };
-    write_parcel(&mut stream, &req_parcel);
-    let res_parcel = match read_parcel(&mut stream) {
-        Ok(parcel) => parcel,
-        Err(e) => {
-            error!("Invalid parcel! {}", e);
-            return;
-        }
-    };
-    match res_parcel.parcel_type {
-        Type::SeqRes => {
-            if let Body::SeqRes { seq_number } = res_parcel.body {
-                tx.send(seq_number).unwrap();
-            } else {
-                error!("Body-header type mismatch!");
-                return;
-            }
+    let res_parcel = conn.request(req_parcel.clone()).await.inspect_err(|e| error!("{}: {}", e, conn.addr()))?;
+    match res_parcel.body {
+        Body::SeqRes { seq_number } => {
+            Ok(seq_number)
         }
 
-        Type::ProtoError => {}
-        _ => {
-            error!("Unexpected response type to SeqReq, {}", res_parcel.parcel_type);
-            return;
+        Body::Error { code, detail, .. } => Err(Error::Protocol { code, detail }),
+        body => {
+            error!("Unexpected response type to SeqReq, {}", body.parcel_type());
+            Err(Error::unexpected(Type::SeqRes, body.parcel_type()))
         }
     }
-}
\ No newline at end of file
+}
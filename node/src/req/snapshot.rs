@@ -0,0 +1,42 @@
+use std::sync::Arc;
+use crate::proto::{ProtoParcel, Body, Snapshot, Type};
+use crate::pool::Connection;
+use crate::error::{Error, Result};
+use log::{error, info};
+
+/*
+    Retrieves a snapshot from the first host provided that answers with one, or the last error
+    if none of them does
+ */
+pub async fn fetch_snapshot(neighbour_list: &[Arc<Connection>]) -> Result<Snapshot> {
+    let req = ProtoParcel::snapshot_req();
+
+    let mut last_error = Error::Membership("no neighbour to fetch a snapshot from".to_string());
+    for conn in neighbour_list {
+        match fetch(conn, &req).await {
+            Ok(snapshot) => {
+                info!("Fetched snapshot at sequence number {} from {}", snapshot.sequence, conn.addr());
+                return Ok(snapshot);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+async fn fetch(conn: &Connection, req_parcel: &ProtoParcel) -> Result<Snapshot> {
+    info!("Fetching snapshot from {}", conn.addr());
+
+    let res_parcel = conn.request(req_parcel.clone()).await.inspect_err(|e| error!("{}: {}", e, conn.addr()))?;
+    match res_parcel.body {
+        Body::SnapshotRes { snapshot } => {
+            Ok(snapshot)
+        }
+
+        Body::Error { code, detail, .. } => Err(Error::Protocol { code, detail }),
+        body => {
+            error!("Unexpected response type to SnapshotReq, {}", body.parcel_type());
+            Err(Error::unexpected(Type::SnapshotRes, body.parcel_type()))
+        }
+    }
+}
@@ -4,7 +4,8 @@ use std::collections::BinaryHeap;
 /// The goal of this data structure is to provide an explicit ordering of async events in a multi-threaded
 /// process. It holds a sequence of event handles with a defined total ordering between them. A
 /// thread can then call `wait_until` and will be blocked until all the event handles before it
-/// are resolved.
+/// are resolved. Tasks running on an async runtime should use `wait_until_async` instead, which
+/// yields to the runtime rather than blocking a worker thread.
 pub struct OrdSemaphore<T: Ord> {
     events: Mutex<BinaryHeap<Reverse<Waiter<T>>>>,
 }
@@ -41,6 +42,37 @@ impl<T: Ord> OrdSemaphore<T> {
                 .wait_until_consumed()
         }
     }
+
+    /// Whether all tasks before `event` have been completed. Never blocks.
+    pub fn is_clear(&self, event: &T) -> bool {
+        let mut queue = self.events.lock().unwrap();
+        loop {
+            match queue.peek() {
+                None => return true,
+                Some(w) if w.0.event >= *event => return true,
+                Some(w) if w.0.watcher.is_consumed() => drop(queue.pop()),
+                Some(_) => return false,
+            }
+        }
+    }
+
+    /// Suspends the calling task until all tasks before `event` have been completed.
+    pub async fn wait_until_async(&self, event: &T) {
+        loop {
+            let watcher = {
+                let mut queue = self.events.lock().unwrap();
+                loop {
+                    match queue.peek() {
+                        None => return,
+                        Some(w) if w.0.event >= *event => return,
+                        Some(w) if w.0.watcher.is_consumed() => drop(queue.pop()),
+                        Some(w) => break w.0.watcher.clone(),
+                    }
+                }
+            };
+            watcher.consumed().await
+        }
+    }
 }
 
 fn create_pair<T: Ord>(event: T) -> (Client, Waiter<T>) {
@@ -87,6 +119,7 @@ use std::sync::{
     atomic::{self, AtomicBool},
     Arc, Condvar, Mutex,
 };
+use tokio::sync::Notify;
 
 ///
 /// The `Client` is given to the creator of the event and belongs to the execution context.
@@ -97,6 +130,7 @@ struct Inner {
     done: AtomicBool,
     done_mutex: Mutex<bool>,
     cv: Condvar,
+    notify: Notify,
 }
 
 impl Client {
@@ -108,6 +142,7 @@ impl Client {
             self.0.done.store(true, atomic::Ordering::Release);
             *self.0.done_mutex.lock().unwrap() = true;
             self.0.cv.notify_all();
+            self.0.notify.notify_waiters();
         }
     }
 
@@ -116,6 +151,7 @@ impl Client {
             done: false.into(),
             done_mutex: Mutex::new(false),
             cv: Condvar::new(),
+            notify: Notify::new(),
         };
         let client = Client(Arc::new(i));
         let watcher = Watcher(client.0.clone());
@@ -139,4 +175,14 @@ impl Watcher {
             )
         }
     }
+    async fn consumed(self) {
+        loop {
+            // register interest before checking so a concurrent `consume` isn't missed
+            let notified = self.0.notify.notified();
+            if self.is_consumed() {
+                return;
+            }
+            notified.await;
+        }
+    }
 }
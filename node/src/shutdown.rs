@@ -0,0 +1,48 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::UnboundedSender;
+
+use log::{info, warn};
+
+use crate::engine::OrderingEngine;
+use crate::internal::TaskSignal;
+use crate::outbox::flush_now;
+use crate::req::push_state::push_state;
+use crate::state::{Mode, State};
+
+/// Resolves once the process is asked to stop with SIGTERM or SIGINT.
+pub async fn stop_requested() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Couldn't listen for SIGINT");
+
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        _ = interrupt.recv() => info!("Received SIGINT"),
+    }
+}
+
+/// Takes the node out of the cluster.
+///
+/// Client publishes are refused from here on. Those already in flight get up to `grace` to be
+/// delivered and are cancelled after that. Neighbours are then told the node is leaving, the
+/// heartbeat is stopped and releases still held back are sent.
+pub async fn shutdown(state: Arc<RwLock<State>>, engine: Arc<dyn OrderingEngine>, heartbeat: UnboundedSender<TaskSignal>, grace: Duration) {
+    info!("Shutting down, no longer accepting publishes");
+    state.write().unwrap().change_mode(Mode::Shutdown);
+
+    if tokio::time::timeout(grace, engine.cleared()).await.is_err() {
+        warn!("Cancelling publishes still in flight after {:?}", grace);
+    }
+
+    let _ = heartbeat.send(TaskSignal::StopProcess);
+
+    // releases owed to neighbours go out before they forget about this node
+    flush_now(&state).await;
+
+    let neighbours = state.read().unwrap().get_neighbour_connections();
+    if let Err(e) = push_state(&neighbours, Mode::Shutdown).await {
+        warn!("Not every neighbour knows this node is shutting down, {}", e);
+    }
+}
@@ -1,6 +1,4 @@
 use std::collections::{HashMap, HashSet};
-use sha2::{Sha256, Digest};
-use byteorder::{ReadBytesExt, BigEndian};
 use num_derive::{FromPrimitive, ToPrimitive};
 
 
@@ -11,7 +9,15 @@ use serde::export::Formatter;
 use std::fmt;
 use std::net::SocketAddr;
 use crate::state::Mode::Wrk;
-use crate::proto::set_sender_id;
+use crate::proto::{set_sender_id, get_max_parcel_size, Snapshot, Cluster, Version, MessageWrapper, PROTO_VERSION};
+use crate::pool::{ConnectionPool, Connection};
+use crate::outbox::ReleaseOutbox;
+use crate::history::History;
+use crate::membership::Membership;
+use std::sync::Arc;
+use std::cmp::max;
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
 
 
 #[derive(FromPrimitive, ToPrimitive, Deserialize, Serialize, Clone, PartialEq)]
@@ -22,6 +28,8 @@ pub enum Mode {
     Panic = 4,
     Shutdown = 5,
     TimedOut = 6,
+    // failure detector thinks it may be down, still counted as working until it times out
+    Suspected = 7,
 }
 
 impl Display for Mode {
@@ -32,32 +40,80 @@ impl Display for Mode {
             Mode::Err => write!(f, "{}", "Err"),
             Mode::Panic => write!(f, "{}", "Panic"),
             Mode::Shutdown => write!(f, "{}", "Shutdown"),
-            Mode::TimedOut => write!(f, "{}", "TimedOut")
+            Mode::TimedOut => write!(f, "{}", "TimedOut"),
+            Mode::Suspected => write!(f, "Suspected")
         }
     }
 }
 
+/// The cluster-wide sequence number of the last message delivered on this node.
+///
+/// Every node delivers messages in the same order, so counting them gives each one the same number
+/// on every node. Shared with the ordering engine, which stamps messages as they leave the critical section.
+pub struct Sequence(AtomicU64);
+
+impl Sequence {
+    pub fn new() -> Sequence {
+        Sequence(AtomicU64::new(0))
+    }
+
+    /// The high-water mark, 0 before anything was delivered.
+    pub fn current(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Takes the sequence number of the next delivered message.
+    pub fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Goes back to `sequence`, for a node that numbered messages differently from the cluster.
+    pub fn rewind_to(&self, sequence: u64) {
+        self.0.store(sequence, Ordering::SeqCst);
+    }
+
+    /// Catches up to `sequence` if it's ahead, never going back.
+    pub fn advance_to(&self, sequence: u64) {
+        self.0.fetch_max(sequence, Ordering::SeqCst);
+    }
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Sequence::new()
+    }
+}
+
 pub struct State {
-    pub id: u16,
+    pub id: Uuid,
     pub mode: Mode,
     pub name: String,
+    pub cluster: Cluster,
     pub internal_addr: SocketAddr,
     pub external_addr: Option<SocketAddr>,
 
-    pub neighbours: HashMap<u16, Node>,
-    pub sequence: u8,
+    pub neighbours: HashMap<Uuid, Node>,
+    // number of members the cluster is configured with, the majority is taken out of it even when
+    // fewer nodes are known
+    pub size: usize,
+    pub sequence: Arc<Sequence>,
     pub current_lock: [u8; 32],
+
+    pub pool: ConnectionPool,
+    pub outbox: Arc<ReleaseOutbox>,
+    pub history: Arc<History>,
+    pub membership: Arc<Membership>,
+    // sequence number of the last message each subscribed client consumed
+    pub offsets: HashMap<u64, u64>,
 }
 
 impl State {
-    pub fn new(mode: Mode, name: String,
+    pub fn new(id: Uuid, mode: Mode, name: String,
                internal_addr: SocketAddr, external_addr: Option<SocketAddr>,
-               neighbours: HashMap<u16, Node>) -> Self {
-        let id = Sha256::digest(name.as_bytes()).as_slice().read_u16::<BigEndian>().unwrap();
-
+               neighbours: HashMap<Uuid, Node>) -> Self {
         set_sender_id(id);
 
-        State { id, mode, name, internal_addr, external_addr, neighbours, sequence: 0, current_lock: [0; 32] }
+        State { id, mode, name, cluster: Cluster { name: String::new(), id: None }, internal_addr, external_addr, neighbours, size: 1, sequence: Arc::new(Sequence::new()), current_lock: [0; 32], pool: ConnectionPool::new(), outbox: Arc::new(ReleaseOutbox::new()), history: Arc::new(History::default()), membership: Arc::new(Membership::new()), offsets: HashMap::new() }
     }
 
     pub fn get_node_information(&self) -> Node {
@@ -65,9 +121,10 @@ impl State {
             id: self.id,
             mode: self.mode.clone(),
             name: self.name.clone(),
-            // Intended panic. If an external node is requesting self state,
-            // external address should be known.
-            external_addr: self.external_addr.clone().unwrap(),
+            // until it's known, the one listened on is the best guess. Nodes contacted for discovery
+            // use the address they were reached on instead.
+            external_addr: self.external_addr.unwrap_or(self.internal_addr),
+            version: PROTO_VERSION,
         }
     }
 
@@ -91,21 +148,95 @@ impl State {
         neighbour_list
     }
 
-    pub fn get_neighbour_keys(&self) -> HashSet<u16> {
+    pub fn get_neighbour_connections(&self) -> Vec<Arc<Connection>> {
+        self.get_active_neighbours().iter().map(|node| self.pool.get(node)).collect()
+    }
+
+    pub fn get_active_neighbour_keys(&self) -> Vec<Uuid> {
+        self.neighbours.values().filter(|val| val.is_active()).map(|node| node.id).collect()
+    }
+
+    pub fn get_neighbour_keys(&self) -> HashSet<Uuid> {
         self.neighbours.keys().cloned().collect()
     }
 
+    // Nodes needed for a majority of the configured members, or of every node known if there are
+    // more of those. Nodes that time out or leave still count, so the majority never shrinks with them.
+    pub fn quorum(&self) -> usize {
+        quorum(max(self.size, self.neighbours.len() + 1))
+    }
+
+    // Whether this node and its working neighbours make up a majority of the cluster. Only the
+    // majority side of a partition may keep ordering.
+    pub fn has_quorum(&self) -> bool {
+        self.get_active_neighbours().len() + 1 >= self.quorum()
+    }
+
     pub fn get_active_neighbours(&self) -> Vec<Node> {
-        self.neighbours.values().filter(|val| val.mode == Wrk).cloned().collect()
+        self.neighbours.values().filter(|val| val.is_active()).cloned().collect()
     }
+
+    // Point-in-time view of the message log, consumer offsets and membership
+    pub fn snapshot(&self) -> Snapshot {
+        let sequence = self.sequence.current();
+        let mut members: Vec<Node> = self.neighbours.values().cloned().collect();
+        if self.external_addr.is_some() {
+            members.push(self.get_node_information());
+        }
+
+        // as much of the log as leaves room in the parcel for the rest
+        let budget = get_max_parcel_size() / 2;
+        let mut size = 0;
+        let mut messages: Vec<MessageWrapper> = self.history.after(0, usize::MAX).into_iter().rev()
+            .take_while(|message| {
+                size += message.message.len();
+                size <= budget
+            })
+            .collect();
+        messages.reverse();
+
+        Snapshot {
+            sequence,
+            log_start: messages.first().map_or(sequence + 1, |message| message.sequence),
+            messages,
+            offsets: self.offsets.clone(),
+            members,
+        }
+    }
+
+    // Continues from `snapshot`, taking the messages of its log that come after the local sequence
+    // number. Local offsets are kept where they're ahead and nodes already known keep their state.
+    pub fn install(&mut self, snapshot: Snapshot) {
+        let sequence = self.sequence.current();
+        for message in snapshot.messages.into_iter().filter(|message| message.sequence > sequence) {
+            self.history.record(message);
+        }
+        self.sequence.advance_to(snapshot.sequence);
+        for (client, offset) in snapshot.offsets {
+            let local = self.offsets.entry(client).or_insert(offset);
+            *local = max(*local, offset);
+        }
+        for node in snapshot.members {
+            if node.id != self.id && !self.neighbours.contains_key(&node.id) {
+                self.add_neighbour(node);
+            }
+        }
+    }
+}
+
+// Nodes needed for a majority out of `members`
+pub fn quorum(members: usize) -> usize {
+    members / 2 + 1
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Node {
-    pub id: u16,
+    pub id: Uuid,
     pub mode: Mode,
     pub name: String,
     pub external_addr: SocketAddr,
+    // newest protocol version the node speaks
+    pub version: Version,
 }
 
 impl Hash for Node {
@@ -123,10 +254,14 @@ impl PartialEq for Node {
 impl Eq for Node {}
 
 impl Node {
+    // A node with a fresh id
     pub fn new(name: String, mode: Mode, host: SocketAddr) -> Node {
-        let id = Sha256::digest(name.as_bytes()).as_slice().read_u16::<BigEndian>().unwrap();
+        Node { name, mode, external_addr: host, id: Uuid::new_v4(), version: PROTO_VERSION }
+    }
 
-        Node { name, mode, external_addr: host, id }
+    // Working, or suspected but not yet timed out
+    pub fn is_active(&self) -> bool {
+        self.mode == Wrk || self.mode == Mode::Suspected
     }
 }
 
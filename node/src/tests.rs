@@ -1,7 +1,56 @@
 #[cfg(test)]
 mod tests {
+    use crate::net::{read_parcel, write_parcel};
+    use crate::proto::ProtoParcel;
     use crate::semaphore::OrdSemaphore;
+    use crate::state::{State, Node, Mode};
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::net::SocketAddr;
     use std::sync::Arc;
+    use tokio::net::{TcpListener, TcpStream};
+    use uuid::Uuid;
+
+    // A working node named "a" without neighbours, at an address nothing listens on
+    fn state(id: Uuid) -> State {
+        let addr = "127.0.0.1:7878".parse().unwrap();
+        State::new(id, Mode::Wrk, "a".to_string(), addr, Some(addr), HashMap::new())
+    }
+
+    // Hands every connection to a new listener over to `serve`, returning the listener's address
+    async fn listen<F, T>(serve: F) -> SocketAddr
+        where F: Fn(TcpStream, SocketAddr) -> T + Send + 'static, T: Future<Output = ()> + Send + 'static {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer_addr) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream, peer_addr));
+            }
+        });
+        addr
+    }
+
+    // A neighbour named `name` answering each parcel with what `respond` makes of it, if anything
+    async fn mock_peer<F>(name: &str, respond: F) -> Node
+        where F: Fn(ProtoParcel) -> Option<ProtoParcel> + Send + Sync + 'static {
+        let respond = Arc::new(respond);
+        let addr = listen(move |mut stream, _| {
+            let respond = respond.clone();
+            async move {
+                while let Ok(parcel) = read_parcel(&mut stream).await {
+                    let id = parcel.id;
+                    if let Some(mut response) = respond(parcel) {
+                        response.id = id;
+                        if write_parcel(&mut stream, &response).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }).await;
+        Node::new(name.to_string(), Mode::Wrk, addr)
+    }
 
     #[test]
     fn test1() {
@@ -53,4 +102,603 @@ mod tests {
         t0.join().unwrap();
         t1.join().unwrap();
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test4() {
+        use std::time::Duration;
+        let s = Arc::new(OrdSemaphore::new());
+        let c = s.create_task(0);
+        let s_ = s.clone();
+        let t0 = tokio::spawn(async move {
+            s_.wait_until_async(&1).await;
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!t0.is_finished());
+        c.consume();
+        t0.await.unwrap();
+    }
+
+    #[test]
+    fn test5() {
+        let s = OrdSemaphore::new();
+        let c = s.create_task(1);
+        assert!(s.is_clear(&1));
+        assert!(!s.is_clear(&2));
+        c.consume();
+        assert!(s.is_clear(&2));
+    }
+
+    #[test]
+    fn test6() {
+        let mut state = state(Uuid::new_v4());
+        let addr = state.internal_addr;
+        assert!(state.has_quorum());
+        state.add_neighbour(Node::new("b".to_string(), Mode::Wrk, addr));
+        state.add_neighbour(Node::new("c".to_string(), Mode::TimedOut, addr));
+        assert!(state.has_quorum());
+        state.add_neighbour(Node::new("d".to_string(), Mode::TimedOut, addr));
+        assert!(!state.has_quorum());
+    }
+
+    #[test]
+    fn test7() {
+        use crate::history::History;
+        use crate::proto::MessageWrapper;
+        let history = History::new(3);
+        for sequence in 1..=5 {
+            history.record(MessageWrapper { message: vec![sequence as u8], sequence, receiver_mask: 0 });
+        }
+        let after = |sequence, limit| history.after(sequence, limit).iter().map(|m| m.sequence).collect::<Vec<u64>>();
+        assert_eq!(after(0, 10), vec![3, 4, 5]);
+        assert_eq!(after(3, 10), vec![4, 5]);
+        assert_eq!(after(2, 1), vec![3]);
+        assert!(after(5, 10).is_empty());
+    }
+
+    #[test]
+    fn test8() {
+        use crate::proto::{Gossip, Health};
+        use std::sync::RwLock;
+        let mut state = state(Uuid::new_v4());
+        let b = Node::new("b".to_string(), Mode::Wrk, state.internal_addr);
+        state.add_neighbour(b.clone());
+        let (id, membership) = (state.id, state.membership.clone());
+        let state = Arc::new(RwLock::new(state));
+
+        // suspicion of ourselves is refuted with a higher incarnation
+        membership.receive(&state, vec![Gossip { id, health: Health::Suspect, incarnation: 0 }]);
+        let refutation = membership.piggyback();
+        assert_eq!(refutation.len(), 1);
+        assert!(refutation[0].id == id && refutation[0].health == Health::Alive && refutation[0].incarnation == 1);
+
+        // a member the cluster found dead is timed out
+        membership.receive(&state, vec![Gossip { id: b.id, health: Health::Dead, incarnation: 0 }]);
+        assert!(state.read().unwrap().neighbours[&b.id].mode == Mode::TimedOut);
+    }
+
+    #[test]
+    fn test9() {
+        use crate::proto::Cluster;
+        let cluster = |name: &str, id| Cluster { name: name.to_string(), id };
+        let id = Uuid::new_v4();
+        assert!(cluster("a", Some(id)).accepts(&cluster("a", Some(id))));
+        // a node that hasn't joined yet doesn't know the id
+        assert!(cluster("a", Some(id)).accepts(&cluster("a", None)));
+        assert!(!cluster("a", Some(id)).accepts(&cluster("b", None)));
+        assert!(!cluster("a", Some(id)).accepts(&cluster("a", Some(Uuid::new_v4()))));
+    }
+
+    #[test]
+    fn test10() {
+        use crate::proto::{Handshake, Version, ProtoErrorKind};
+        let handshake = |min, max, features: &[&str]| Handshake {
+            min_version: Version { major: 1, minor: min },
+            max_version: Version { major: 1, minor: max },
+            features: features.iter().map(|f| f.to_string()).collect(),
+        };
+        // an upgraded node talks to the others in the old version
+        assert_eq!(handshake(5, 6, &[]).negotiate(&handshake(5, 5, &[])), Ok(Version { major: 1, minor: 5 }));
+        assert!(matches!(handshake(6, 6, &[]).negotiate(&handshake(5, 5, &[])), Err(ProtoErrorKind::VersionMismatch { .. })));
+        assert!(matches!(handshake(5, 5, &["raft"]).negotiate(&handshake(5, 5, &[])), Err(ProtoErrorKind::FeatureMismatch { .. })));
+        // optional features are ignored
+        assert!(handshake(5, 5, &["raft"]).negotiate(&handshake(5, 5, &["raft", "compression"])).is_ok());
+    }
+
+    #[test]
+    fn test11() {
+        use crate::Error;
+        use crate::net::is_acked;
+        use crate::proto::ProtoError;
+        // the requester learns why, not just that it failed
+        let error = ProtoParcel::error(ProtoError::Overloaded, "too many requests".to_string(), 7);
+        assert!(matches!(is_acked(error, 7), Err(Error::Protocol { code: ProtoError::Overloaded, .. })));
+        assert!(is_acked(ProtoParcel::ack(7), 7).is_ok());
+        assert!(matches!(is_acked(ProtoParcel::ack(8), 7), Err(Error::Protocol { code: ProtoError::UnexpectedType, .. })));
+    }
+
+    #[test]
+    fn test12() {
+        use crate::proto::{ParcelHeader, Body, Type, Cluster};
+        // older versions get the old format, with the type next to the body
+        let mut parcel = ProtoParcel::forward(vec![1, 2, 3]);
+        parcel.proto_version = "1.6".to_string();
+        let decoded = ProtoParcel::decode(&parcel.encode().unwrap()).unwrap();
+        assert!(matches!(decoded.body, Body::Forward { ref message } if message == &[1, 2, 3]));
+
+        let mut parcel = ProtoParcel::ext_addr_req();
+        parcel.proto_version = "1.6".to_string();
+        assert!(ProtoParcel::decode(&parcel.encode().unwrap()).unwrap().parcel_type() == Type::ExtAddrReq);
+        let node = Node::new("a".to_string(), Mode::Wrk, "127.0.0.1:7878".parse().unwrap());
+        let mut parcel = ProtoParcel::dsc_res(vec![node.clone()], node, Cluster { name: "a".to_string(), id: None });
+        parcel.proto_version = "1.6".to_string();
+        assert!(ProtoParcel::decode(&parcel.encode().unwrap()).unwrap().parcel_type() == Type::DscRes);
+
+        // the header tells the type from the body alone
+        let header: ParcelHeader = serde_cbor::from_slice(&ProtoParcel::seq_req().encode().unwrap()).unwrap();
+        assert!(header.parcel_type == Some(Type::SeqReq));
+        let header: ParcelHeader = serde_cbor::from_slice(&ProtoParcel::ack(1).encode().unwrap()).unwrap();
+        assert!(header.parcel_type == Some(Type::Ack));
+    }
+
+    #[tokio::test]
+    async fn test13() {
+        use crate::proto::{Body, get_max_parcel_size};
+        // a parcel bigger than a chunk comes out whole
+        let mut buf = vec![];
+        write_parcel(&mut buf, &ProtoParcel::forward(vec![7; 200 * 1024])).await.unwrap();
+        let parcel = read_parcel(&mut buf.as_slice()).await.unwrap();
+        assert!(matches!(parcel.body, Body::Forward { ref message } if message.len() == 200 * 1024));
+
+        // one claiming to be over the limit is refused without being read
+        let size = get_max_parcel_size() as u64 + 1;
+        assert!(read_parcel(&mut size.to_le_bytes().as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test14() {
+        use crate::pool::Connection;
+        use crate::proto::{Body, PROTO_VERSION};
+        // a peer answering a whole batch of requests in reverse order
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut requests = vec![];
+            for _ in 0..16 {
+                requests.push(read_parcel(&mut stream).await.unwrap());
+            }
+            for request in requests.into_iter().rev() {
+                let mut response = request.clone();
+                response.is_response = true;
+                write_parcel(&mut stream, &response).await.unwrap();
+            }
+        });
+
+        // every reply makes it to the request it answers
+        let connection = Arc::new(Connection::open(addr, PROTO_VERSION));
+        let requests: Vec<_> = (0..16u8).map(|i| {
+            let connection = connection.clone();
+            tokio::spawn(async move { (i, connection.request(ProtoParcel::forward(vec![i])).await) })
+        }).collect();
+        for request in requests {
+            let (i, response) = request.await.unwrap();
+            assert!(matches!(response.unwrap().body, Body::Forward { ref message } if message == &[i]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test15() {
+        use crate::pool::Connection;
+        use crate::proto::PROTO_VERSION;
+        // a peer that drops every connection after answering once
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = read_parcel(&mut stream).await.unwrap();
+                write_parcel(&mut stream, &request).await.unwrap();
+            }
+        });
+
+        let connection = Connection::open(addr, PROTO_VERSION);
+        assert!(connection.request(ProtoParcel::forward(vec![1])).await.is_ok());
+        // requests fail until the connection is back up
+        let mut answered = false;
+        for _ in 0..20 {
+            if connection.request(ProtoParcel::forward(vec![2])).await.is_ok() {
+                answered = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(answered);
+        accepted.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test16() {
+        use crate::engine::OrderingEngine;
+        use crate::engine::lamport::LamportEngine;
+        use crate::proto::{ResourceRequest, Body, ReleaseMode};
+        use std::sync::RwLock;
+        use std::time::Duration;
+        let mut state = state(Uuid::new_v4());
+        let b = Node::new("b".to_string(), Mode::Wrk, state.internal_addr);
+        state.add_neighbour(b.clone());
+        let outbox = state.outbox.clone();
+        let engine = LamportEngine::new(Arc::new(RwLock::new(state)), Duration::from_millis(0), ReleaseMode::Deferred);
+
+        // a release held back for b goes out with the grant of b's next request
+        let (_, rel) = ResourceRequest::generate(b"a".to_vec());
+        outbox.defer(&[b.id], std::slice::from_ref(&rel));
+        let (mut req, _) = ResourceRequest::generate(b"b".to_vec());
+        req.owner = b.id;
+        let mut parcel = ProtoParcel::resource_request(req);
+        parcel.sender_id = b.id;
+        let grant = engine.handle(parcel).await.unwrap();
+        assert!(matches!(grant.body, Body::Grant { accepted: 1, ref resource_releases, .. }
+            if resource_releases.len() == 1 && resource_releases[0].shorthand == rel.shorthand));
+        assert!(outbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test17() {
+        use crate::outbox::flush;
+        use crate::proto::{ResourceRequest, Body};
+        use std::sync::RwLock;
+        use std::time::{Duration, Instant};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut state = state(Uuid::new_v4());
+        let b = Node::new("b".to_string(), Mode::Wrk, listener.local_addr().unwrap());
+        state.add_neighbour(b.clone());
+        let outbox = state.outbox.clone();
+        let linger = Duration::from_millis(100);
+
+        // without a grant to carry it, the release is sent on its own once the linger is up
+        let (_, rel) = ResourceRequest::generate(b"a".to_vec());
+        let deferred = Instant::now();
+        outbox.defer(&[b.id], std::slice::from_ref(&rel));
+        tokio::spawn(flush(Arc::new(RwLock::new(state)), linger));
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let parcel = read_parcel(&mut stream).await.unwrap();
+        assert!(deferred.elapsed() >= linger);
+        assert!(matches!(parcel.body, Body::ResourceRelease { ref resource_release } if resource_release.shorthand == rel.shorthand));
+        write_parcel(&mut stream, &ProtoParcel::ack(parcel.id)).await.unwrap();
+        assert!(outbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test18() {
+        use crate::engine::OrderingEngine;
+        use crate::engine::raft::RaftEngine;
+        use crate::proto::Body;
+        use std::sync::RwLock;
+        use std::time::Duration;
+        let dir = std::env::temp_dir().join(format!("piko-{}", Uuid::new_v4()));
+        let id = Uuid::new_v4();
+        let open = || {
+            RaftEngine::open(Arc::new(RwLock::new(state(id))), &dir, Duration::from_millis(100), Duration::from_millis(20)).unwrap()
+        };
+        let vote = |candidate| {
+            let mut parcel = ProtoParcel::vote_req(1, 0, 0);
+            parcel.sender_id = candidate;
+            parcel
+        };
+        let granted = |response: Option<ProtoParcel>| matches!(response.unwrap().body, Body::VoteRes { granted: true, .. });
+        let (b, c) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert!(granted(open().handle(vote(b)).await));
+        // the vote outlives a restart, there is no second one in the same term
+        let engine = open();
+        assert!(!granted(engine.handle(vote(c)).await));
+        assert!(granted(engine.handle(vote(b)).await));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test19() {
+        use crate::engine::OrderingEngine;
+        use crate::engine::raft::RaftEngine;
+        use crate::proto::Body;
+        use std::sync::RwLock;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+        // a peer that votes for and stores whatever it's sent, and stops answering while it's down
+        async fn peer(name: &str) -> (Node, Arc<AtomicBool>) {
+            let down = Arc::new(AtomicBool::new(false));
+            let down_ref = down.clone();
+            let node = mock_peer(name, move |parcel| match parcel.body {
+                _ if down_ref.load(Ordering::SeqCst) => None,
+                Body::VoteReq { term, .. } => Some(ProtoParcel::vote_res(term, true)),
+                Body::AppendReq { term, prev_log_index, ref entries, .. } => Some(ProtoParcel::append_res(term, true, prev_log_index + entries.len() as u64)),
+                _ => Some(ProtoParcel::ack(parcel.id)),
+            }).await;
+            (node, down)
+        }
+        let (b, b_down) = peer("b").await;
+        let (c, c_down) = peer("c").await;
+
+        let dir = std::env::temp_dir().join(format!("piko-{}", Uuid::new_v4()));
+        let mut state = state(Uuid::new_v4());
+        state.add_neighbour(b);
+        state.add_neighbour(c);
+        let history = state.history.clone();
+        let engine = Arc::new(RaftEngine::open(Arc::new(RwLock::new(state)), &dir, Duration::from_millis(100), Duration::from_millis(20)).unwrap());
+        engine.bootstrap().unwrap();
+        let running = engine.clone();
+        tokio::spawn(async move { running.run().await });
+
+        let publish = |message: &'static str| {
+            let engine = engine.clone();
+            async move {
+                for _ in 0..50 {
+                    if engine.publish(message.as_bytes().to_vec()).await.is_ok() {
+                        return true;
+                    }
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                false
+            }
+        };
+
+        // the node is elected on its own and adds b and c to the configuration
+        assert!(publish("m1").await);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // a majority of the three commits, whichever node is missing
+        c_down.store(true, Ordering::SeqCst);
+        assert!(publish("m2").await);
+        c_down.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        b_down.store(true, Ordering::SeqCst);
+        assert!(publish("m3").await);
+        // without a majority nothing is committed
+        c_down.store(true, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(engine.publish(b"m4".to_vec()).await.is_err());
+
+        let messages: Vec<Vec<u8>> = history.after(0, 10).into_iter().map(|m| m.message).collect();
+        assert_eq!(messages, vec![b"m1".to_vec(), b"m2".to_vec(), b"m3".to_vec()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test20() {
+        use crate::engine::OrderingEngine;
+        use crate::engine::raft::RaftEngine;
+        use crate::proto::{Body, LogEntry};
+        use std::sync::RwLock;
+        use std::time::Duration;
+        let dir = std::env::temp_dir().join(format!("piko-{}", Uuid::new_v4()));
+        let (id, leader) = (Uuid::new_v4(), Uuid::new_v4());
+        let open = || {
+            let state = state(id);
+            let history = state.history.clone();
+            (RaftEngine::open(Arc::new(RwLock::new(state)), &dir, Duration::from_millis(100), Duration::from_millis(20)).unwrap(), history)
+        };
+        let entry = |term, message: &str| LogEntry { term, owner: leader, message: Some(message.as_bytes().to_vec()), members: None };
+        let append = |term, prev_log_index, prev_log_term, entries, leader_commit| {
+            let mut parcel = ProtoParcel::append_req(term, prev_log_index, prev_log_term, entries, leader_commit);
+            parcel.sender_id = leader;
+            parcel
+        };
+        let stored = |response: Option<ProtoParcel>| match response.unwrap().body {
+            Body::AppendRes { success: true, match_index, .. } => Some(match_index),
+            _ => None,
+        };
+
+        let (engine, _) = open();
+        assert_eq!(stored(engine.handle(append(1, 0, 0, vec![entry(1, "a"), entry(1, "b")], 0)).await), Some(2));
+        // a later leader overwrites the uncommitted entry the node doesn't share with it
+        assert_eq!(stored(engine.handle(append(2, 1, 1, vec![entry(2, "c")], 0)).await), Some(2));
+        drop(engine);
+
+        // and so does the log on disk
+        let (engine, history) = open();
+        assert_eq!(stored(engine.handle(append(2, 2, 1, vec![], 2)).await), None);
+        assert_eq!(stored(engine.handle(append(2, 2, 2, vec![], 2)).await), Some(2));
+        let messages: Vec<Vec<u8>> = history.after(0, 10).into_iter().map(|m| m.message).collect();
+        assert_eq!(messages, vec![b"a".to_vec(), b"c".to_vec()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test21() {
+        use crate::engine::OrderingEngine;
+        use crate::engine::lamport::LamportEngine;
+        use crate::error::Error;
+        use crate::proto::{ResourceRequest, ReleaseMode};
+        use crate::req::publish::pub_req;
+        use std::sync::RwLock;
+        use std::time::Duration;
+        use tokio::sync::{mpsc, oneshot};
+        // a node of a three node cluster that the other two left or were cut off from
+        let mut a = state(Uuid::new_v4());
+        a.size = 3;
+        assert!(!a.has_quorum());
+        let engine = LamportEngine::new(Arc::new(RwLock::new(a)), Duration::from_millis(0), ReleaseMode::Broadcast);
+        assert!(matches!(engine.publish(b"a".to_vec()).await, Err(Error::NoQuorum)));
+
+        // acknowledged by every neighbour it reaches isn't enough without a majority
+        let b = mock_peer("b", |parcel| Some(ProtoParcel::ack(parcel.id))).await;
+        let state = state(Uuid::new_v4());
+        let neighbours = vec![state.pool.get(&b)];
+        let (releases, _) = mpsc::unbounded_channel();
+        for (quorum, acked) in [(3, false), (2, true)] {
+            let (req, _) = ResourceRequest::generate(b"a".to_vec());
+            let (waiter, result) = oneshot::channel();
+            pub_req(&neighbours, quorum, vec![req], vec![waiter], &releases).await;
+            assert_eq!(result.await.unwrap().is_ok(), acked);
+        }
+    }
+
+    #[tokio::test]
+    async fn test22() {
+        use crate::history::History;
+        use crate::proto::MessageWrapper;
+        use crate::req::replicate::catch_up;
+        use crate::state::Sequence;
+        // a host whose log only holds messages past the ones asked for
+        let b = mock_peer("b", |_| {
+            let messages = (10..12).map(|sequence| MessageWrapper { message: vec![], sequence, receiver_mask: 0 }).collect();
+            Some(ProtoParcel::catch_up_res(messages))
+        }).await;
+        let state = state(Uuid::new_v4());
+        let (sequence, history) = (Sequence::new(), History::default());
+
+        // the messages up to 5 are skipped rather than asked for forever
+        let caught_up = tokio::time::timeout(std::time::Duration::from_secs(5), catch_up(&[state.pool.get(&b)], &sequence, &history, 5)).await;
+        assert!(matches!(caught_up, Ok(Err(_))));
+        assert_eq!(sequence.current(), 5);
+        assert!(history.after(0, 10).is_empty());
+    }
+
+    #[test]
+    fn test23() {
+        use crate::proto::{MessageWrapper, Snapshot};
+        let mut a = state(Uuid::new_v4());
+        a.add_neighbour(Node::new("c".to_string(), Mode::Wrk, a.internal_addr));
+        for sequence in 1..=3 {
+            a.sequence.next();
+            a.history.record(MessageWrapper { message: vec![sequence as u8], sequence, receiver_mask: 0 });
+        }
+        a.offsets.insert(7, 2);
+
+        // a node that delivered the first message takes the rest of the log from the snapshot
+        let encoded = serde_cbor::to_vec(&a.snapshot()).unwrap();
+        let snapshot: Snapshot = serde_cbor::from_slice(&encoded).unwrap();
+        assert_eq!((snapshot.sequence, snapshot.log_start), (3, 1));
+        let mut b = state(Uuid::new_v4());
+        b.sequence.next();
+        b.history.record(MessageWrapper { message: vec![1], sequence: 1, receiver_mask: 0 });
+        b.install(snapshot);
+        assert_eq!(b.sequence.current(), 3);
+        let messages: Vec<(u64, Vec<u8>)> = b.history.after(0, 10).into_iter().map(|m| (m.sequence, m.message)).collect();
+        assert_eq!(messages, vec![(1, vec![1]), (2, vec![2]), (3, vec![3])]);
+        assert_eq!(b.offsets.get(&7), Some(&2));
+        assert_eq!(b.neighbours.len(), 2);
+    }
+
+    #[test]
+    fn test24() {
+        use crate::phi::{Arrivals, DEFAULT_PHI_THRESHOLD};
+        use std::time::Duration;
+        let mut arrivals = Arrivals::new();
+        arrivals.heartbeat();
+        let estimate = Duration::from_secs(1);
+
+        // suspicion grows the longer the member is silent
+        let phis: Vec<f64> = (0..=6).map(|half| arrivals.phi_after(estimate, Duration::from_millis(500 * half))).collect();
+        assert!(phis.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // intervals of about a second with a quarter of that as deviation cross the threshold
+        // some 5.2 deviations past the mean
+        assert!(arrivals.phi_after(estimate, Duration::from_millis(2250)) < DEFAULT_PHI_THRESHOLD);
+        assert!(arrivals.phi_after(estimate, Duration::from_millis(2350)) >= DEFAULT_PHI_THRESHOLD);
+    }
+
+    #[tokio::test]
+    async fn test25() {
+        use crate::tls::{Tls, Listener};
+        use std::process::Command;
+        let dir = std::env::temp_dir().join(format!("piko-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data = dir.join("a");
+        assert!(Command::new("sh").arg("conf/gen-tls.sh").arg(&dir).arg("127.0.0.1").arg(&data).output().unwrap().status.success());
+        let tls = Arc::new(Tls::load(&data.join("node.pem"), &data.join("node.key"), &dir.join("ca.pem"), None).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = tls.clone();
+        let accepted = tokio::spawn(async move {
+            let mut results = vec![];
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                results.push(match acceptor.accept(stream, Listener::Cluster).await {
+                    Ok(mut stream) => read_parcel(&mut stream).await.is_ok(),
+                    Err(_) => false,
+                });
+            }
+            results
+        });
+
+        // a node presenting a certificate signed by the CA gets through
+        let mut stream = tls.connect(TcpStream::connect(addr).await.unwrap(), &addr).await.unwrap();
+        write_parcel(&mut stream, &ProtoParcel::seq_req()).await.unwrap();
+        // one talking in the clear doesn't
+        let mut plain = TcpStream::connect(addr).await.unwrap();
+        let _ = write_parcel(&mut plain, &ProtoParcel::seq_req()).await;
+
+        assert_eq!(accepted.await.unwrap(), vec![true, false]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test26() {
+        use crate::engine::lamport::LamportEngine;
+        use crate::net::{serve, Shared};
+        use crate::proto::{ProtoError, Body, ReleaseMode};
+        use crate::tls::{Tls, Listener};
+        use std::process::Command;
+        use std::sync::RwLock;
+        use std::time::Duration;
+        let dir = std::env::temp_dir().join(format!("piko-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tls = |name: &str| {
+            let data = dir.join(name);
+            assert!(Command::new("sh").arg("conf/gen-tls.sh").arg(&dir).arg("127.0.0.1").arg(&data).output().unwrap().status.success());
+            Tls::load(&data.join("node.pem"), &data.join("node.key"), &dir.join("ca.pem"), None).unwrap()
+        };
+        let (a_tls, b_tls) = (Arc::new(tls("a")), tls("b"));
+
+        let mut state = state(a_tls.node_id());
+        let mut b = Node::new("b".to_string(), Mode::Wrk, state.internal_addr);
+        b.id = b_tls.node_id();
+        state.add_neighbour(b);
+        let state = Arc::new(RwLock::new(state));
+        let engine = Arc::new(LamportEngine::new(state.clone(), Duration::from_millis(0), ReleaseMode::Broadcast));
+        let shared = Shared { state, engine };
+        let addr = listen(move |stream, peer_addr| {
+            let (tls, shared) = (a_tls.clone(), shared.clone());
+            async move {
+                if let Ok(stream) = tls.accept(stream, Listener::Cluster).await {
+                    serve(stream, peer_addr, shared).await;
+                }
+            }
+        }).await;
+
+        // b is answered in its own name
+        let mut stream = b_tls.connect(TcpStream::connect(addr).await.unwrap(), &addr).await.unwrap();
+        let mut seq_req = ProtoParcel::seq_req();
+        seq_req.sender_id = b_tls.node_id();
+        write_parcel(&mut stream, &seq_req).await.unwrap();
+        assert!(matches!(read_parcel(&mut stream).await.unwrap().body, Body::SeqRes { .. }));
+
+        // but can't shut another node down
+        let mut shutdown = ProtoParcel::state_change(Mode::Shutdown);
+        shutdown.sender_id = Uuid::new_v4();
+        write_parcel(&mut stream, &shutdown).await.unwrap();
+        assert!(matches!(read_parcel(&mut stream).await.unwrap().body, Body::Error { code: ProtoError::Unauthenticated, .. }));
+
+        // and a parcel without a certificate isn't read at all
+        let mut plain = TcpStream::connect(addr).await.unwrap();
+        let _ = write_parcel(&mut plain, &seq_req).await;
+        assert!(read_parcel(&mut plain).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test27() {
+        use crate::auth::{seal, open};
+        let parcel = ProtoParcel::seq_req().encode().unwrap();
+
+        // only a parcel sealed with the cluster's secret is opened
+        assert_eq!(open(b"secret", seal(b"secret", parcel.clone())).unwrap(), parcel);
+        assert!(open(b"secret", parcel.clone()).is_err());
+        assert!(open(b"secret", seal(b"other", parcel.clone())).is_err());
+        let mut tampered = seal(b"secret", parcel);
+        tampered[0] ^= 1;
+        assert!(open(b"secret", tampered).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use lazy_static::lazy_static;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use uuid::Uuid;
+
+use crate::auth::unauthenticated;
+use crate::error::Result;
+
+lazy_static! {
+    static ref TLS: Mutex<Option<Arc<Tls>>> = Mutex::new(None);
+}
+
+/// TLS for every connection the node makes or takes, set up from PEM files.
+///
+/// The node presents `cert` to whoever connects to it and only trusts nodes presenting a certificate
+/// signed by `ca`. A node is expected to be issued its certificate for the address it's contacted
+/// on, unless every node's certificate names `server_name`. Authentication is mutual on the cluster
+/// socket: a node presents its certificate when connecting as well, and connections without one
+/// signed by `ca` are refused, so only members of the cluster get to talk to it. Clients only
+/// authenticate the node.
+///
+/// A node's certificate names the node's id as a `urn:uuid:` URI, which is what the node speaks for
+/// when it connects to another.
+pub struct Tls {
+    cluster_acceptor: TlsAcceptor,
+    client_acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    server_name: Option<ServerName<'static>>,
+    id: Uuid,
+}
+
+/// The listener a connection was taken by.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Listener {
+    Cluster,
+    Client,
+}
+
+impl Tls {
+    pub fn load(cert: &Path, key: &Path, ca: &Path, server_name: Option<String>) -> Result<Tls> {
+        let provider = Arc::new(default_provider());
+        let certs = read_certs(cert)?;
+        let id = node_id(&certs[0]).map_err(|e| in_file(cert, io::Error::new(ErrorKind::InvalidData, e.to_string())))?;
+        let key = read_key(key)?;
+        let mut roots = RootCertStore::empty();
+        for ca_cert in read_certs(ca)? {
+            roots.add(ca_cert).map_err(invalid)?;
+        }
+        let roots = Arc::new(roots);
+
+        let members = WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone()).build().map_err(invalid)?;
+        let cluster = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions().map_err(invalid)?
+            .with_client_cert_verifier(members)
+            .with_single_cert(certs.clone(), key.clone_key()).map_err(invalid)?;
+        let clients = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions().map_err(invalid)?
+            .with_no_client_auth()
+            .with_single_cert(certs.clone(), key.clone_key()).map_err(invalid)?;
+        let client = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions().map_err(invalid)?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key).map_err(invalid)?;
+        let server_name = match server_name {
+            Some(name) => Some(ServerName::try_from(name).map_err(invalid)?),
+            None => None,
+        };
+
+        Ok(Tls {
+            cluster_acceptor: TlsAcceptor::from(Arc::new(cluster)),
+            client_acceptor: TlsAcceptor::from(Arc::new(clients)),
+            connector: TlsConnector::from(Arc::new(client)),
+            server_name,
+            id,
+        })
+    }
+
+    /// Id of the node the certificate was issued to.
+    pub fn node_id(&self) -> Uuid {
+        self.id
+    }
+
+    pub(crate) async fn connect(&self, stream: TcpStream, addr: &SocketAddr) -> Result<Stream> {
+        let name = self.server_name.clone().unwrap_or_else(|| ServerName::IpAddress(addr.ip().into()));
+        let stream = self.connector.connect(name, stream).await?;
+        Ok(Stream::Tls(Box::new(TlsStream::Client(stream))))
+    }
+
+    pub(crate) async fn accept(&self, stream: TcpStream, listener: Listener) -> Result<Stream> {
+        let acceptor = match listener {
+            Listener::Cluster => &self.cluster_acceptor,
+            Listener::Client => &self.client_acceptor,
+        };
+        let stream = acceptor.accept(stream).await?;
+        Ok(Stream::Tls(Box::new(TlsStream::Server(stream))))
+    }
+}
+
+/// TLS the node uses, if it's enabled.
+pub fn get_tls() -> Option<Arc<Tls>> {
+    TLS.lock().unwrap().clone()
+}
+
+pub fn set_tls(tls: Tls) {
+    *TLS.lock().unwrap() = Some(Arc::new(tls));
+}
+
+/// Secures a connection made to `addr`, if TLS is enabled.
+pub async fn secure_outgoing(stream: TcpStream, addr: &SocketAddr) -> Result<Stream> {
+    match get_tls() {
+        Some(tls) => tls.connect(stream, addr).await,
+        None => Ok(Stream::Plain(stream)),
+    }
+}
+
+/// Secures a connection taken by `listener`, if TLS is enabled.
+pub async fn secure_incoming(stream: TcpStream, listener: Listener) -> Result<Stream> {
+    match get_tls() {
+        Some(tls) => tls.accept(stream, listener).await,
+        None => Ok(Stream::Plain(stream)),
+    }
+}
+
+/// A connection, encrypted or not.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.tcp().set_nodelay(nodelay)
+    }
+
+    /// Id of the node the peer's certificate was issued to, none if it didn't present one.
+    pub fn peer_id(&self) -> Result<Option<Uuid>> {
+        let certs = match self {
+            Stream::Plain(_) => return Ok(None),
+            Stream::Tls(stream) => stream.get_ref().1.peer_certificates(),
+        };
+        match certs.and_then(|certs| certs.first()) {
+            Some(cert) => node_id(cert).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+// Id of the node `cert` was issued to, named by a `urn:uuid:` URI
+fn node_id(cert: &CertificateDer<'_>) -> Result<Uuid> {
+    let cert = webpki::EndEntityCert::try_from(cert).map_err(|e| unauthenticated(&format!("unreadable certificate, {}", e)))?;
+    let id = cert.valid_uri_names()
+        .find_map(|uri| uri.strip_prefix("urn:uuid:"))
+        .ok_or_else(|| unauthenticated("certificate doesn't name a node id"))?;
+    Uuid::parse_str(id).map_err(|e| unauthenticated(&format!("certificate names an invalid node id, {}", e)))
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| in_file(path, e))?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>().map_err(|e| in_file(path, e))?;
+    if certs.is_empty() {
+        return Err(in_file(path, io::Error::new(ErrorKind::InvalidData, "no certificate found")).into());
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| in_file(path, e))?);
+    match rustls_pemfile::private_key(&mut reader).map_err(|e| in_file(path, e))? {
+        Some(key) => Ok(key),
+        None => Err(in_file(path, io::Error::new(ErrorKind::InvalidData, "no private key found")).into()),
+    }
+}
+
+fn in_file(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+fn invalid<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e)
+}
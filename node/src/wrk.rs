@@ -1,110 +1,201 @@
-use crate::state::{State, Mode};
+use crate::state::{State, Mode, Sequence};
 use std::sync::{RwLock, Arc, Mutex};
 
 
-use crate::req::{push_state::push_state, seq_recovery::seq_recovery};
+use crate::req::{push_state::push_state, seq_recovery::seq_recovery, replicate::catch_up, snapshot::fetch_snapshot};
+use crate::history::History;
 
-use log::{info, debug, error};
-use crossbeam_channel::{Receiver};
-use crate::proto::{ResourceRequest, ResourceRelease};
+use log::{info, debug, warn, error};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::Notify;
+use crate::proto::{ResourceRequest, ResourceRelease, ReleaseMode};
+use crate::error::{Error, Result};
 use std::collections::{BinaryHeap, HashMap};
 
 use crate::req::publish::pub_rel;
-use std::time::Duration;
 
 
-static SLEEP_TIME_MILLIS: u64 = 10;
-
-// Tasked with maintaining protocol consistency
-pub fn wrk(state: Arc<RwLock<State>>, resource_queue: Arc<Mutex<BinaryHeap<ResourceRequest>>>,
-           recv: &Receiver<ResourceRelease>, pending_messages: Arc<Mutex<HashMap<u64, (ResourceRelease, bool)>>>) {
-    let mut state_ref = state.write().unwrap();
+/// Worker ends of the channels feeding `wrk`.
+pub struct Receivers {
+    pub releases: UnboundedReceiver<ResourceRelease>,
+    pub queue_changed: UnboundedReceiver<()>,
+    pub acks: UnboundedReceiver<u64>,
+}
 
-    let self_id = state_ref.id;
+// Tasked with maintaining protocol consistency.
+// The loop is driven by three event sources: changes to the resource queue, acknowledgements of
+// local requests and releases from neighbours. It blocks until one of them fires.
+pub async fn wrk(state: Arc<RwLock<State>>, resource_queue: Arc<Mutex<BinaryHeap<ResourceRequest>>>,
+                 receivers: &mut Receivers, pending_messages: Arc<Mutex<HashMap<u64, (ResourceRelease, bool)>>>,
+                 progress: Arc<Notify>, release_mode: ReleaseMode) {
+    let Receivers { releases: recv, queue_changed, acks } = receivers;
+    let (self_id, neighbours, sequence, history) = {
+        let state_ref = state.read().unwrap();
+        (state_ref.id, state_ref.get_neighbour_connections(), state_ref.sequence.clone(), state_ref.history.clone())
+    }; // release state lock before network calls
 
-    if state_ref.get_cluster_size() == 0 {}
     info!("Acquiring sequence number");
-    let neighbours = state_ref.get_neighbour_addrs();
-    let seq_num = seq_recovery(&neighbours);
+    let seq_num = seq_recovery(&neighbours).await.unwrap_or_else(|e| {
+        warn!("Couldn't recover the sequence number, {}", e);
+        0
+    });
+
+    // A node that is behind starts from a snapshot, which carries as much of the log as a neighbour
+    // still holds
+    if sequence.current() < seq_num {
+        if let Ok(snapshot) = fetch_snapshot(&neighbours).await {
+            if sequence.current() < snapshot.sequence {
+                if sequence.current() + 1 < snapshot.log_start {
+                    warn!("Messages {} to {} are no longer held by any neighbour", sequence.current() + 1, snapshot.log_start - 1);
+                }
+                info!("Starting from snapshot at sequence number {} with messages from {}", snapshot.sequence, snapshot.log_start);
+                state.write().unwrap().install(snapshot);
+            }
+        }
+    }
 
-    state_ref.sequence = seq_num;
+    // Stream in what was delivered while we were away before taking part
+    if let Err(e) = catch_up(&neighbours, &sequence, &history, seq_num).await {
+        warn!("{}", e);
+    }
 
     // Send state to neighbours
-    push_state(&neighbours, Mode::Wrk);
-    info!("Starting from sequence number: {}", seq_num);
-    // release state lock
-    drop(state_ref);
+    if let Err(e) = push_state(&neighbours, Mode::Wrk).await {
+        warn!("Not every neighbour knows this node is working, {}", e);
+    }
+    info!("Starting from sequence number: {}", sequence.current());
 
+    // Releases can reach us out of order. Those of different nodes take different connections, and
+    // deferred ones come both with grants and on their own. They are held here until their request
+    // makes it to the head of the queue.
+    let deferred = release_mode == ReleaseMode::Deferred;
+    let mut released: HashMap<u64, ResourceRelease> = HashMap::new();
 
     loop {
-        let q_ref = &resource_queue.clone();
-        let mut q_lock = q_ref.lock().unwrap();
-        let (req_owner, req_key) = match q_lock.peek() {
-            None => {
-                // Queue was empty
-                drop(q_lock);
-                std::thread::sleep(Duration::from_millis(SLEEP_TIME_MILLIS));
-                continue;
-            }
-            Some(req) => {
-                (req.owner, req.shorthand)
-            }
+        let (neighbour_keys, outbox) = {
+            let state_ref = state.read().unwrap();
+            (state_ref.get_active_neighbour_keys(), state_ref.outbox.clone())
         };
-        if req_owner == self_id && is_acknowledged(pending_messages.clone(), req_key) {
-            // begin executing CS
-            debug!("Current req: {} Me: {} Hash: {}", req_owner, self_id, req_key);
-
-            let resource = q_lock.pop().unwrap();
-            info!("Entering CS! node {} hash {}", resource.owner, resource.shorthand);
-            let mut messages = pending_messages.lock().unwrap();
-            let message = messages.remove(&resource.shorthand).unwrap();
-
-            // drop before slow ops
-            drop(q_lock);
-            let state = state.read().unwrap();
-
-            pub_rel(&state.get_neighbour_addrs(), message.0);
-        } else {
-            // gather resource releases
-            drop(q_lock);
-            match recv.try_recv() {
-                Ok(rel) => {
-                    if req_owner == rel.owner {
-                        let mut q_lock = q_ref.lock().unwrap();
-                        let pledge = q_lock.pop().unwrap();
-                        info!("Neighbour exited CS! node {} message {}", pledge.owner, String::from_utf8(rel.message.message).unwrap());
-                        drop(q_lock);
-                    } else {
-                        error!("Neighbour tried entering CS without lock!");
+
+        let messages = {
+            let mut q_lock = resource_queue.lock().unwrap();
+
+            // every acknowledged request of ours at the head of the queue is released in one batch
+            let mut messages = vec![];
+            while let Some((req_owner, req_key)) = q_lock.peek().map(|req| (req.owner, req.shorthand)) {
+                if req_owner != self_id {
+                    match released.remove(&req_key) {
+                        Some(rel) => {
+                            q_lock.pop();
+                            let seq = stamp_foreign(&sequence, &history, &rel).unwrap_or_else(|e| {
+                                error!("{}, resyncing", e);
+                                resync(&sequence, &history, &rel)
+                            });
+                            info!("Neighbour exited CS! node {} seq {} message {}", req_owner, seq, String::from_utf8_lossy(&rel.message.message));
+                            progress.notify_waiters();
+                            continue;
+                        }
+                        None => break,
                     }
                 }
-                Err(_) => {
-                    std::thread::sleep(Duration::from_millis(SLEEP_TIME_MILLIS));
+                if !is_acknowledged(&pending_messages, req_key) {
+                    break;
                 }
-            };
+                // begin executing CS
+                debug!("Current req: {} Me: {} Hash: {}", req_owner, self_id, req_key);
+
+                let resource = q_lock.pop().unwrap();
+                // kept pending until the release is out, so the node doesn't look clear before that
+                let mut rel = pending_messages.lock().unwrap().get(&resource.shorthand).unwrap().0.clone();
+                rel.sequence = sequence.next();
+                rel.message.sequence = rel.sequence;
+                history.record(rel.message.clone());
+                info!("Entering CS! node {} hash {} seq {}", resource.owner, resource.shorthand, rel.sequence);
+                messages.push(rel);
+            }
+            if deferred && !messages.is_empty() {
+                // still under the queue lock, so a grant never sees the request gone without its release
+                outbox.defer(&neighbour_keys, &messages);
+                forget_pending(&pending_messages, &messages);
+                progress.notify_waiters();
+                messages.clear();
+            }
+            messages
+        }; // drop before slow ops
+
+        if !messages.is_empty() {
+            let neighbours = state.read().unwrap().get_neighbour_connections();
+
+            let sent = messages.clone();
+            if let Err(e) = pub_rel(&neighbours, messages).await {
+                warn!("Not every neighbour took the releases, {}", e);
+            }
+            forget_pending(&pending_messages, &sent);
+            progress.notify_waiters();
+            continue;
+        }
+
+        // block until something that could let the head of the queue progress happens
+        tokio::select! {
+            _ = queue_changed.recv() => {}
+            _ = acks.recv() => {}
+            Some(rel) = recv.recv() => {
+                released.insert(rel.shorthand, rel);
+            }
         }
     }
 }
 
-fn is_acknowledged(map: Arc<Mutex<HashMap<u64, (ResourceRelease, bool)>>>, rel_key: u64) -> bool {
-    const TRYOUTS: u8 = 3;
-    let mut response = false;
+// Records a neighbour's message under the sequence number its owner stamped it with. As everyone
+// delivers in the same order that's the next one here too. A node that joined while messages were
+// in flight can be behind, and catches up to the owner here. One that already used the number
+// ordered messages differently, which is an ordering error.
+fn stamp_foreign(sequence: &Sequence, history: &History, rel: &ResourceRelease) -> Result<u64> {
+    let expected = sequence.current() + 1;
+    if rel.sequence < expected {
+        return Err(Error::Ordering(format!("node {} stamped a message {} but {} were delivered here", rel.owner, rel.sequence, expected - 1)));
+    }
+    if rel.sequence > expected {
+        warn!("Sequence {} of node {} is ahead of local {}", rel.sequence, rel.owner, expected);
+    }
+    Ok(record(sequence, history, rel))
+}
 
-    // try a few times
-    for _i in 0..TRYOUTS {
-        let map = map.lock().unwrap();
-        response = match map.get(&rel_key) {
-            Some(rel) => {
-                rel.1
-            }
-            _ => {
-                // Misses in the hash map could be a symptom of random collisions (very low chance)
-                // or protocol bugs.
-                false
-            }
-        };
-        drop(map);
-        if !response { std::thread::sleep(Duration::from_millis(10)); }
+// Takes up the numbering of the owner after an ordering error, dropping the messages numbered
+// from there on here.
+fn resync(sequence: &Sequence, history: &History, rel: &ResourceRelease) -> u64 {
+    let before = rel.sequence.saturating_sub(1);
+    warn!("Dropping messages {} to {} from the history", before + 1, sequence.current());
+    history.truncate_after(before);
+    sequence.rewind_to(before);
+    record(sequence, history, rel)
+}
+
+fn record(sequence: &Sequence, history: &History, rel: &ResourceRelease) -> u64 {
+    sequence.advance_to(rel.sequence);
+    let mut message = rel.message.clone();
+    message.sequence = rel.sequence;
+    history.record(message);
+    rel.sequence
+}
+
+fn forget_pending(map: &Mutex<HashMap<u64, (ResourceRelease, bool)>>, released: &[ResourceRelease]) {
+    let mut map = map.lock().unwrap();
+    for rel in released {
+        map.remove(&rel.shorthand);
+    }
+}
+
+fn is_acknowledged(map: &Mutex<HashMap<u64, (ResourceRelease, bool)>>, rel_key: u64) -> bool {
+    let map = map.lock().unwrap();
+    match map.get(&rel_key) {
+        Some(rel) => {
+            rel.1
+        }
+        _ => {
+            // Misses in the hash map could be a symptom of random collisions (very low chance)
+            // or protocol bugs.
+            false
+        }
     }
-    response
-}
\ No newline at end of file
+}